@@ -0,0 +1,105 @@
+/* Generated from ffi/src/lib.rs by `cargo test -p journalr-ffi`; do not edit. */
+#ifndef JOURNALR_H
+#define JOURNALR_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/** Outcome of a call. The values are stable; new ones are only added. */
+typedef enum JournalrStatus {
+    JOURNALR_OK = 0,
+    JOURNALR_WRONG_PASSWORD = 1,
+    JOURNALR_INVALID_FORMAT = 2,
+    JOURNALR_OUT_OF_RANGE_SIZE = 3,
+    JOURNALR_NOT_FOUND = 4,
+    JOURNALR_NOT_ACCESSIBLE = 5,
+    JOURNALR_KEYFILE_REQUIRED = 6,
+    JOURNALR_WRONG_KEYFILE = 7,
+    JOURNALR_NO_RECOVERY_KEY = 8,
+    JOURNALR_LEGACY_FORMAT = 9,
+    JOURNALR_NOT_ENCRYPTED = 10,
+    JOURNALR_ALREADY_EXISTS = 11,
+    JOURNALR_IDENTITY_REQUIRED = 12,
+    JOURNALR_NOT_A_RECIPIENT = 13,
+    JOURNALR_NO_WAY_TO_UNLOCK = 14,
+    /** The diary was changed by something else since it was opened or last saved. */
+    JOURNALR_CHANGED_ON_DISK = 15,
+    /** A pointer was null, a string was not UTF-8 or a date was not YYYY-MM-DD. */
+    JOURNALR_INVALID_ARGUMENT = 100,
+    /** The text contains a NUL byte, so it cannot be passed as a C string. */
+    JOURNALR_INVALID_TEXT = 101,
+    /** A bug in the library stopped the call part way; the diary is best closed without saving. */
+    JOURNALR_INTERNAL = 200,
+} JournalrStatus;
+
+/** An unlocked diary. */
+typedef struct JournalrDiary JournalrDiary;
+
+/**
+ * Unlock the diary at path (a file, a directory or a database) and store it in *out.
+ * password may be null for a diary stored as plaintext.
+ *
+ * # Safety
+ * path and password must be null or NUL-terminated, and out must be null or valid for
+ * writes.
+ */
+JournalrStatus journalr_open(const char *path, const char *password, JournalrDiary **out);
+
+/**
+ * Forget the diary without saving it. Null is ignored.
+ *
+ * # Safety
+ * diary must be null or come from journalr_open, and is not to be used afterwards.
+ */
+void journalr_close(JournalrDiary *diary);
+
+/**
+ * Store the dates that have entries in *out, oldest first and one per line.
+ *
+ * # Safety
+ * diary must be null or come from journalr_open, and out must be null or valid for
+ * writes.
+ */
+JournalrStatus journalr_dates(const JournalrDiary *diary, char **out);
+
+/**
+ * Store the entry for date in *out, or null if there is none.
+ *
+ * # Safety
+ * diary must be null or come from journalr_open, date must be null or NUL-terminated,
+ * and out must be null or valid for writes.
+ */
+JournalrStatus journalr_read(const JournalrDiary *diary, const char *date, char **out);
+
+/**
+ * Replace the entry for date with text. Nothing is written until journalr_save.
+ *
+ * # Safety
+ * diary must be null or come from journalr_open, and date and text must be null or
+ * NUL-terminated.
+ */
+JournalrStatus journalr_write(JournalrDiary *diary, const char *date, const char *text);
+
+/**
+ * Write the diary back to where it was opened from, unless something else changed it there
+ * since it was opened or last saved.
+ *
+ * # Safety
+ * diary must be null or come from journalr_open.
+ */
+JournalrStatus journalr_save(JournalrDiary *diary);
+
+/**
+ * Overwrite and free a string from this library. Null is ignored.
+ *
+ * # Safety
+ * s must be null or a string stored by this library that was not freed yet.
+ */
+void journalr_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
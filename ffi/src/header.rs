@@ -0,0 +1,177 @@
+//! Generating `include/journalr.h` from the declarations in `lib.rs`.
+//!
+//! The test fails if the header is missing or out of date; run it with
+//! `JOURNALR_UPDATE_SNAPSHOTS=1` to write the new one. Declarations the generator does not
+//! understand fail it with the offending line.
+use std::{fs, path::PathBuf};
+
+const SOURCE: &str = include_str!("lib.rs");
+
+/// The C spelling of a Rust parameter or return type.
+fn c_type(rust: &str) -> String {
+    if let Some(pointee) = rust.strip_prefix("*const ") {
+        format!("const {} *", c_type(pointee))
+    } else if let Some(pointee) = rust.strip_prefix("*mut ") {
+        let pointee = c_type(pointee);
+        match pointee.ends_with('*') {
+            true => format!("{pointee}*"),
+            false => format!("{pointee} *"),
+        }
+    } else if rust == "c_char" {
+        "char".to_owned()
+    } else {
+        rust.to_owned()
+    }
+}
+/// `JournalrStatus::NotFound` as `JOURNALR_NOT_FOUND`.
+fn c_constant(variant: &str) -> String {
+    let mut name = "JOURNALR".to_owned();
+    for c in variant.chars() {
+        if c.is_uppercase() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+/// The doc comment `lines` as a C comment indented by `indent`.
+fn comment(lines: &[&str], indent: &str) -> String {
+    match lines {
+        [] => String::new(),
+        [line] => format!("{indent}/** {line} */\n"),
+        lines => {
+            let mut comment = format!("{indent}/**\n");
+            for line in lines {
+                comment.push_str(format!("{indent} * {line}").trim_end());
+                comment.push('\n');
+            }
+            comment + indent + " */\n"
+        }
+    }
+}
+fn doc_text(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("///")
+        .map(|doc| doc.strip_prefix(' ').unwrap_or(doc))
+}
+
+pub fn generate() -> String {
+    let source = SOURCE.replace("[`", "").replace("`]", "").replace('`', "");
+    let mut lines = source.lines();
+    let mut docs = Vec::new();
+    let mut status = String::new();
+    let mut functions = String::new();
+    while let Some(line) = lines.next() {
+        if let Some(doc) = doc_text(line) {
+            docs.push(doc);
+            continue;
+        }
+        if line.starts_with("pub enum JournalrStatus") {
+            status = comment(&docs, "");
+            status.push_str("typedef enum JournalrStatus {\n");
+            docs.clear();
+            for line in lines.by_ref().take_while(|line| *line != "}") {
+                match doc_text(line) {
+                    Some(doc) => docs.push(doc),
+                    None => {
+                        status.push_str(&comment(&docs, "    "));
+                        docs.clear();
+                        let (name, value) = line
+                            .trim()
+                            .trim_end_matches(',')
+                            .split_once(" = ")
+                            .unwrap_or_else(|| {
+                                panic!("JournalrStatus variant needs an explicit value: {line}")
+                            });
+                        status.push_str(&format!("    {} = {value},\n", c_constant(name)));
+                    }
+                }
+            }
+            status.push_str("} JournalrStatus;\n");
+        } else if line.starts_with("pub unsafe extern \"C\" fn ") {
+            let mut signature = line.to_owned();
+            while !signature.ends_with('{') {
+                let next = lines
+                    .next()
+                    .unwrap_or_else(|| panic!("signature never reaches its body: {line}"));
+                signature.push_str(next.trim());
+            }
+            let signature = signature
+                .trim_start_matches("pub unsafe extern \"C\" fn ")
+                .trim_end_matches('{')
+                .replace(",)", ")");
+            let (name, rest) = signature
+                .split_once('(')
+                .unwrap_or_else(|| panic!("signature without parameters: {signature}"));
+            let (params, ret) = rest
+                .split_once(')')
+                .unwrap_or_else(|| panic!("parameters are not closed: {signature}"));
+            let ret = ret
+                .trim()
+                .strip_prefix("-> ")
+                .map_or("void".to_owned(), c_type);
+            let mut params: Vec<_> = params
+                .split(',')
+                .filter(|param| !param.trim().is_empty())
+                .map(|param| {
+                    let (name, ty) = param.trim().split_once(": ").unwrap_or_else(|| {
+                        panic!("parameter `{param}` is not `name: type` in {signature}")
+                    });
+                    let ty = c_type(ty);
+                    match ty.ends_with('*') {
+                        true => format!("{ty}{name}"),
+                        false => format!("{ty} {name}"),
+                    }
+                })
+                .collect();
+            if params.is_empty() {
+                params.push("void".to_owned());
+            }
+            // The safety requirements are the same in C, so they stay in the comment.
+            functions.push('\n');
+            functions.push_str(&comment(&docs, ""));
+            functions.push_str(&format!("{ret} {name}({});\n", params.join(", ")));
+        }
+        if !line.starts_with('#') {
+            docs.clear();
+        }
+    }
+    format!(
+        "/* Generated from ffi/src/lib.rs by `cargo test -p journalr-ffi`; do not edit. */
+#ifndef JOURNALR_H
+#define JOURNALR_H
+
+#ifdef __cplusplus
+extern \"C\" {{
+#endif
+
+{status}
+/** An unlocked diary. */
+typedef struct JournalrDiary JournalrDiary;
+{functions}
+#ifdef __cplusplus
+}}
+#endif
+
+#endif
+"
+    )
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/journalr.h");
+    let header = generate();
+    let update = std::env::var_os("JOURNALR_UPDATE_SNAPSHOTS").is_some();
+    match fs::read_to_string(&path) {
+        Ok(current) if current == header => (),
+        Ok(_) if !update => {
+            panic!("include/journalr.h is out of date; rerun with JOURNALR_UPDATE_SNAPSHOTS=1")
+        }
+        Err(_) if !update => {
+            panic!("include/journalr.h is missing; rerun with JOURNALR_UPDATE_SNAPSHOTS=1")
+        }
+        _ => fs::write(&path, header)
+            .unwrap_or_else(|e| panic!("cannot write {}: {e}", path.display())),
+    }
+}
@@ -0,0 +1,271 @@
+//! C interface to journalr diaries, declared in `include/journalr.h`.
+//!
+//! Every fallible function returns a [`JournalrStatus`]. Strings are NUL-terminated UTF-8 and
+//! dates are `YYYY-MM-DD`. Strings handed out are released with [`journalr_string_free`] and
+//! diaries with [`journalr_close`].
+use std::{
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use chrono::NaiveDate;
+use journalr::{secret::Zeroize, Credentials, Date, DiaryFromFileError, Session};
+
+#[cfg(test)]
+mod header;
+
+/// Outcome of a call. The values are stable; new ones are only added.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalrStatus {
+    Ok = 0,
+    WrongPassword = 1,
+    InvalidFormat = 2,
+    OutOfRangeSize = 3,
+    NotFound = 4,
+    NotAccessible = 5,
+    KeyfileRequired = 6,
+    WrongKeyfile = 7,
+    NoRecoveryKey = 8,
+    LegacyFormat = 9,
+    NotEncrypted = 10,
+    AlreadyExists = 11,
+    IdentityRequired = 12,
+    NotARecipient = 13,
+    NoWayToUnlock = 14,
+    /// The diary was changed by something else since it was opened or last saved.
+    ChangedOnDisk = 15,
+    /// A pointer was null, a string was not UTF-8 or a date was not `YYYY-MM-DD`.
+    InvalidArgument = 100,
+    /// The text contains a NUL byte, so it cannot be passed as a C string.
+    InvalidText = 101,
+    /// A bug in the library stopped the call part way; the diary is best closed without saving.
+    Internal = 200,
+}
+impl From<DiaryFromFileError> for JournalrStatus {
+    fn from(value: DiaryFromFileError) -> Self {
+        match value {
+            DiaryFromFileError::WrongPassword => Self::WrongPassword,
+            DiaryFromFileError::InvalidFormat => Self::InvalidFormat,
+            DiaryFromFileError::OutOfRangeSize => Self::OutOfRangeSize,
+            DiaryFromFileError::NotFound => Self::NotFound,
+            DiaryFromFileError::NotAccessible => Self::NotAccessible,
+            DiaryFromFileError::KeyfileRequired => Self::KeyfileRequired,
+            DiaryFromFileError::WrongKeyfile => Self::WrongKeyfile,
+            DiaryFromFileError::NoRecoveryKey => Self::NoRecoveryKey,
+            DiaryFromFileError::LegacyFormat => Self::LegacyFormat,
+            DiaryFromFileError::NotEncrypted => Self::NotEncrypted,
+            DiaryFromFileError::AlreadyExists => Self::AlreadyExists,
+            DiaryFromFileError::IdentityRequired => Self::IdentityRequired,
+            DiaryFromFileError::NotARecipient => Self::NotARecipient,
+            DiaryFromFileError::NoWayToUnlock => Self::NoWayToUnlock,
+            DiaryFromFileError::ChangedOnDisk => Self::ChangedOnDisk,
+        }
+    }
+}
+/// Run the body of a call, turning a panic into [`JournalrStatus::Internal`] since it must not
+/// unwind into C.
+fn status(body: impl FnOnce() -> Result<(), JournalrStatus>) -> JournalrStatus {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(res) => res.err().unwrap_or(JournalrStatus::Ok),
+        Err(_) => JournalrStatus::Internal,
+    }
+}
+
+/// An unlocked diary.
+pub struct JournalrDiary(Session);
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, JournalrStatus> {
+    if s.is_null() {
+        return Err(JournalrStatus::InvalidArgument);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| JournalrStatus::InvalidArgument)
+}
+unsafe fn date_arg(s: *const c_char) -> Result<Date, JournalrStatus> {
+    NaiveDate::parse_from_str(str_arg(s)?, "%Y-%m-%d")
+        .map(Date::from)
+        .map_err(|_| JournalrStatus::InvalidArgument)
+}
+unsafe fn diary_arg<'a>(diary: *const JournalrDiary) -> Result<&'a JournalrDiary, JournalrStatus> {
+    diary.as_ref().ok_or(JournalrStatus::InvalidArgument)
+}
+unsafe fn diary_arg_mut<'a>(
+    diary: *mut JournalrDiary,
+) -> Result<&'a mut JournalrDiary, JournalrStatus> {
+    diary.as_mut().ok_or(JournalrStatus::InvalidArgument)
+}
+/// Store a copy of `s` in `*out`, or null if there is no `s`.
+unsafe fn string_out(s: Option<&str>, out: *mut *mut c_char) -> Result<(), JournalrStatus> {
+    if out.is_null() {
+        return Err(JournalrStatus::InvalidArgument);
+    }
+    let s = match s {
+        Some(s) => CString::new(s)
+            .map_err(|_| JournalrStatus::InvalidText)?
+            .into_raw(),
+        None => ptr::null_mut(),
+    };
+    *out = s;
+    Ok(())
+}
+
+/// Unlock the diary at `path` (a file, a directory or a database) and store it in `*out`.
+/// `password` may be null for a diary stored as plaintext.
+///
+/// # Safety
+/// `path` and `password` must be null or NUL-terminated, and `out` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_open(
+    path: *const c_char,
+    password: *const c_char,
+    out: *mut *mut JournalrDiary,
+) -> JournalrStatus {
+    status(|| {
+        let path = str_arg(path)?;
+        let password = match password.is_null() {
+            true => "",
+            false => str_arg(password)?,
+        };
+        if out.is_null() {
+            return Err(JournalrStatus::InvalidArgument);
+        }
+        let session = Session::open(path, Credentials::Password(password, None))?;
+        *out = Box::into_raw(Box::new(JournalrDiary(session)));
+        Ok(())
+    })
+}
+/// Forget the diary without saving it. Null is ignored.
+///
+/// # Safety
+/// `diary` must be null or come from [`journalr_open`], and is not to be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_close(diary: *mut JournalrDiary) {
+    if !diary.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(diary))));
+    }
+}
+/// Store the dates that have entries in `*out`, oldest first and one per line.
+///
+/// # Safety
+/// `diary` must be null or come from [`journalr_open`], and `out` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_dates(
+    diary: *const JournalrDiary,
+    out: *mut *mut c_char,
+) -> JournalrStatus {
+    status(|| {
+        let dates: Vec<_> = diary_arg(diary)?
+            .0
+            .entries()
+            .map(|(date, _)| date.format("%Y-%m-%d").to_string())
+            .collect();
+        string_out(Some(&dates.join("\n")), out)
+    })
+}
+/// Store the entry for `date` in `*out`, or null if there is none.
+///
+/// # Safety
+/// `diary` must be null or come from [`journalr_open`], `date` must be null or NUL-terminated,
+/// and `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_read(
+    diary: *const JournalrDiary,
+    date: *const c_char,
+    out: *mut *mut c_char,
+) -> JournalrStatus {
+    status(|| string_out(diary_arg(diary)?.0.get(date_arg(date)?), out))
+}
+/// Replace the entry for `date` with `text`. Nothing is written until [`journalr_save`].
+///
+/// # Safety
+/// `diary` must be null or come from [`journalr_open`], and `date` and `text` must be null or
+/// NUL-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_write(
+    diary: *mut JournalrDiary,
+    date: *const c_char,
+    text: *const c_char,
+) -> JournalrStatus {
+    status(|| {
+        let (date, text) = (date_arg(date)?, str_arg(text)?);
+        diary_arg_mut(diary)?.0.put(date, text);
+        Ok(())
+    })
+}
+/// Write the diary back to where it was opened from, unless something else changed it there
+/// since it was opened or last saved.
+///
+/// # Safety
+/// `diary` must be null or come from [`journalr_open`].
+#[no_mangle]
+pub unsafe extern "C" fn journalr_save(diary: *mut JournalrDiary) -> JournalrStatus {
+    status(|| Ok(diary_arg_mut(diary)?.0.save()?))
+}
+/// Overwrite and free a string from this library. Null is ignored.
+///
+/// # Safety
+/// `s` must be null or a string stored by this library that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn journalr_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        let _ = catch_unwind(|| CString::from_raw(s).into_bytes().zeroize());
+    }
+}
+
+#[test]
+fn writes_and_reads_back_through_the_c_interface() {
+    let path = std::env::temp_dir().join(format!("journalr-ffi-{}.jrnl", std::process::id()));
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    let date = |s: &str| CString::new(s).unwrap();
+    unsafe {
+        // A plaintext diary needs no password.
+        std::fs::write(&path, "{\"entries\": {\"01-02-2024\": \"first\"}}").unwrap();
+        let mut diary = ptr::null_mut();
+        assert_eq!(
+            journalr_open(c_path.as_ptr(), ptr::null(), &mut diary),
+            JournalrStatus::Ok
+        );
+        let text = date("second");
+        assert_eq!(
+            journalr_write(diary, date("2024-02-03").as_ptr(), text.as_ptr()),
+            JournalrStatus::Ok
+        );
+        assert_eq!(
+            journalr_write(diary, date("03-02-2024").as_ptr(), text.as_ptr()),
+            JournalrStatus::InvalidArgument
+        );
+        assert_eq!(journalr_save(diary), JournalrStatus::Ok);
+        journalr_close(diary);
+
+        assert_eq!(
+            journalr_open(c_path.as_ptr(), ptr::null(), &mut diary),
+            JournalrStatus::Ok
+        );
+        let mut out = ptr::null_mut();
+        assert_eq!(journalr_dates(diary, &mut out), JournalrStatus::Ok);
+        assert_eq!(CStr::from_ptr(out).to_str(), Ok("2024-02-01\n2024-02-03"));
+        journalr_string_free(out);
+        assert_eq!(
+            journalr_read(diary, date("2024-02-03").as_ptr(), &mut out),
+            JournalrStatus::Ok
+        );
+        assert_eq!(CStr::from_ptr(out).to_str(), Ok("second"));
+        journalr_string_free(out);
+        assert_eq!(
+            journalr_read(diary, date("2024-02-02").as_ptr(), &mut out),
+            JournalrStatus::Ok
+        );
+        assert!(out.is_null());
+        journalr_close(diary);
+    }
+    std::fs::remove_file(path).unwrap();
+}
+#[test]
+fn panics_do_not_unwind_into_c() {
+    assert_eq!(status(|| panic!("bug")), JournalrStatus::Internal);
+}
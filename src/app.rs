@@ -1,61 +1,207 @@
 use std::{
     collections::HashMap,
     error::Error,
-    fs::File,
     io::{self, stderr, Stderr},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use chrono::TimeDelta;
 use crossterm::{
-    event::{self, read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 use ratatui::{
-    backend::CrosstermBackend,
+    backend::{Backend, CrosstermBackend},
     style::{Style, Stylize},
     widgets::Block,
-    Frame, Terminal,
+    Terminal,
 };
 use tui_textarea::TextArea;
 
 use crate::{
-    clear,
+    attempts::{AttemptError, AttemptLog},
+    crypto::{DiaryKey, Identity, Kdf, Keyfile},
     date::Date,
-    diary::{Diary, DiaryFromFileError},
+    diary::{Diary, DiaryFromFileError, Revision, TrashedEntry},
+    events::{EventSource, TerminalEvents},
+    git::GitConfig,
+    journal::Snapshot,
+    lock::{DiaryLock, LockError, LockHolder},
+    secret::{self, wipe, Password, PasswordPolicy, Zeroize},
+    signals,
+    storage::{self, Credentials, FileStorage, Storage},
     ui::{
         centered_input_box, create_file,
         date_selection::{get_date_ui, DateSelection},
         delete_ui,
         editor::{editor_ui, pre_exit_ui},
+        external_change_ui,
+        history::history_ui,
+        lock_held_ui, lock_ui, offer_recovery_key_ui,
         password_form::{password_form_ui, PasswordForm},
+        password_ta, recover_journal_ui, recovery_key_ui,
+        trash::trash_ui,
+        unlock_notice_ui,
     },
+    watch::{self, FileStamp},
 };
 
-pub enum AppMode {
+/// The screen the editor shows, with what that screen keeps between events.
+#[derive(Default)]
+pub enum AppMode<'a> {
+    #[default]
     Edit,
     AskToSave,
-    Password,
+    Password(Prompt<'a>),
+    /// Asking for the keyfile the diary needs; `Esc` gives up with the error that led here.
+    Keyfile(TextArea<'a>, DiaryFromFileError),
+    /// What happened since the diary was last unlocked, shown until a key is pressed.
+    UnlockNotice(String),
     Exit,
-    SetDate,
+    SetDate(DateSelection),
     Delete,
-    GetFile,
+    GetFile(TextArea<'a>),
+    /// Asking whether to create a diary at this path.
+    CreateFile(String),
+    NewPassword(Box<PasswordForm<'a>>),
+    OfferRecoveryKey,
+    /// The recovery key of the new diary, shown only this once.
+    RecoveryKey(secret::Zeroizing<String>),
+    /// Unsaved changes an earlier session left in the journal.
+    RecoverJournal(Snapshot),
+    Locked(Prompt<'a>),
+    /// The trash, with the index of the selected entry counted from the newest.
+    Trash(usize),
+    /// Earlier revisions of the current entry, with the index of the selected one counted from
+    /// the newest.
+    History(usize),
+    /// The file changed on disk, and whether reloading it was refused because it is no longer
+    /// encrypted.
+    ExternalChange(bool),
+    /// The diary on disk now has another password, asked for before it is reloaded or merged.
+    /// The unsaved changes stay meanwhile.
+    Reauthenticate(Prompt<'a>, Rejoin),
+    LockHeld(Option<LockHolder>),
 }
-impl Default for AppMode {
-    fn default() -> Self {
-        Self::Edit
+/// How to take in a diary that changed on disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejoin {
+    /// Replace the open diary with it, discarding unsaved changes.
+    Reload,
+    /// Merge the unsaved changes into it.
+    Merge,
+}
+impl AppMode<'_> {
+    /// Ask for the password of the diary.
+    pub fn password() -> Self {
+        AppMode::Password(Prompt::new("Enter password"))
     }
 }
-pub struct App<'a> {
-    pub path: String,
+/// A masked input for a password or identity path, refusing attempts after recent wrong ones.
+pub struct Prompt<'a> {
+    title: &'static str,
+    ta: TextArea<'a>,
+    /// When attempts may resume; looked up from the attempt log when the prompt is first shown.
+    retry_at: Option<Instant>,
+    /// Why the last attempt failed.
+    error: String,
+}
+impl<'a> Prompt<'a> {
+    fn new(title: &'static str) -> Self {
+        Self {
+            title,
+            ta: password_ta(title),
+            retry_at: None,
+            error: String::new(),
+        }
+    }
+    fn waiting(&self) -> bool {
+        self.retry_at.is_some_and(|at| Instant::now() < at)
+    }
+    /// What was typed, leaving the prompt empty.
+    fn take(&mut self) -> secret::Zeroizing<Vec<String>> {
+        secret::Zeroizing::new(
+            std::mem::replace(&mut self.ta, password_ta(self.title)).into_lines(),
+        )
+    }
+}
+impl Drop for Prompt<'_> {
+    fn drop(&mut self) {
+        wipe(std::mem::take(&mut self.ta));
+    }
+}
+pub struct App<'a, B: Backend = CrosstermBackend<Stderr>> {
+    /// Where the open diary is kept.
+    pub storage: Box<dyn Storage>,
     pub date: Date,
     pub(crate) entries: HashMap<Date, TextArea<'a>>,
-    terminal: Terminal<CrosstermBackend<Stderr>>,
-    pub(crate) mode: AppMode,
+    terminal: Terminal<B>,
+    events: Box<dyn EventSource>,
+    /// Whether `terminal` is the terminal journalr runs in, so raw mode and the alternate
+    /// screen are entered and left with it.
+    tty: bool,
+    pub(crate) mode: AppMode<'a>,
     saved: bool,
-    pub password: String,
+    /// Why the last save, what followed it or journaling the edits since went wrong, shown below
+    /// the editor.
+    notice: Option<String>,
+    pub password: Password,
+    /// Key the open diary is saved with; created along with a new diary.
+    pub(crate) key: Option<DiaryKey>,
+    /// Key derivation settings for new diaries.
+    pub kdf: Kdf,
+    /// Keyfile given on the command line or in the password prompt. New diaries require it.
+    pub keyfile: Option<Keyfile>,
+    /// Identity the diary is opened with instead of a password. New diaries are shared with it.
+    pub identity: Option<Identity>,
+    /// Wrong and successful password attempts, kept across runs.
+    attempts: AttemptLog,
+    /// What new passwords have to satisfy.
+    pub password_policy: PasswordPolicy,
+    /// Pin the password in RAM so it cannot be swapped out.
+    pub mlock: bool,
+    /// Time without input after which the editor is locked. `None` disables auto-locking.
+    pub lock_after: Option<Duration>,
+    /// Show the lock screen after being suspended.
+    pub lock_on_resume: bool,
+    last_activity: Instant,
+    pub(crate) trash: Vec<TrashedEntry>,
+    /// How long deleted entries are kept in the trash. `None` keeps them forever.
+    pub trash_retention: Option<TimeDelta>,
+    /// Date of the entry removed by the most recent delete in this session.
+    last_deleted: Option<Date>,
+    pub(crate) history: HashMap<Date, Vec<Revision>>,
+    /// Text of each entry as of the last save or load, used to detect which entries changed.
+    saved_entries: HashMap<Date, String>,
+    /// Number of earlier versions kept for each entry.
+    pub max_revisions: usize,
+    /// Commit the diary to git after each successful save.
+    pub git: Option<GitConfig>,
+    /// State of the file when it was last loaded or saved.
+    stamp: Option<FileStamp>,
+    /// An outside change the user chose to keep editing over, so it is not reported again.
+    reported_change: Option<FileStamp>,
+    last_check: Instant,
+    /// Held while the diary is open for editing.
+    write_lock: Option<DiaryLock>,
+    /// Digest of the unsaved changes last written to the journal.
+    journaled: Option<u64>,
+    last_journal: Instant,
+    /// Whether the journal left by an earlier session was looked at.
+    journal_checked: bool,
+    /// Show entries without allowing changes.
+    pub read_only: bool,
+}
+impl App<'_> {
+    /// An editor in the terminal journalr runs in.
+    pub fn new() -> io::Result<Self> {
+        let mut app = Self::with_backend(CrosstermBackend::new(stderr()), TerminalEvents)?;
+        app.tty = true;
+        Ok(app)
+    }
 }
-impl<'a> App<'a> {
+impl<'a, B: Backend> App<'a, B> {
     pub(crate) fn setup_input_area(input: &mut TextArea<'_>, date: Date) {
         let block = Block::bordered()
             .title_top(format!(" Diary entry: {} ", date.friendly_format()))
@@ -72,393 +218,1344 @@ impl<'a> App<'a> {
         Self::setup_input_area(&mut input, date);
         input
     }
-    pub fn new() -> io::Result<Self> {
+    /// A single line input box, as for the path of a file.
+    fn input_box(title: &'a str, placeholder: &str) -> TextArea<'a> {
+        let mut input_area = TextArea::default();
+        input_area.set_block(Block::bordered().title_top(title));
+        input_area.set_cursor_style(Style::new().bg(ratatui::style::Color::White));
+        input_area.set_placeholder_text(placeholder);
+        input_area
+    }
+    /// An editor drawing to `backend` and reading `events`, leaving the terminal it runs in
+    /// alone. It starts by asking for the file to open.
+    pub fn with_backend(backend: B, events: impl EventSource + 'static) -> io::Result<Self> {
         let date = Date::today();
         let entries = HashMap::new();
         Ok(App {
-            path: String::new(),
+            storage: Box::new(FileStorage::new("")),
             entries,
             date,
-            terminal: Terminal::new(CrosstermBackend::new(stderr()))?,
-            mode: AppMode::GetFile,
+            terminal: Terminal::new(backend)?,
+            events: Box::new(events),
+            tty: false,
+            mode: AppMode::GetFile(Self::input_box("Enter name of File to open", "")),
             saved: true,
-            password: String::new(),
+            notice: None,
+            password: Password::default(),
+            key: None,
+            kdf: Kdf::default(),
+            keyfile: None,
+            identity: None,
+            password_policy: PasswordPolicy::default(),
+            attempts: AttemptLog::new(),
+            mlock: false,
+            lock_after: Some(Self::DEFAULT_LOCK_AFTER),
+            lock_on_resume: false,
+            last_activity: Instant::now(),
+            trash: Vec::new(),
+            trash_retention: Some(Self::DEFAULT_TRASH_RETENTION),
+            last_deleted: None,
+            history: HashMap::new(),
+            saved_entries: HashMap::new(),
+            max_revisions: Self::DEFAULT_MAX_REVISIONS,
+            git: None,
+            stamp: None,
+            reported_change: None,
+            last_check: Instant::now(),
+            write_lock: None,
+            journaled: None,
+            last_journal: Instant::now(),
+            journal_checked: false,
+            read_only: false,
         })
     }
+    pub const DEFAULT_LOCK_AFTER: Duration = Duration::from_secs(300);
+    pub const DEFAULT_TRASH_RETENTION: TimeDelta = TimeDelta::days(30);
+    pub const DEFAULT_MAX_REVISIONS: usize = Diary::DEFAULT_MAX_REVISIONS;
+    const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+    const JOURNAL_INTERVAL: Duration = Duration::from_secs(2);
+    /// How long to wait for input before looking at the timers again.
+    const FRAME: Duration = Duration::from_millis(16);
+    /// Write the diary, unless the file was changed by something else since it was loaded;
+    /// then the user is asked what to do instead.
     pub fn save(&mut self) {
-        self.saved =  Diary::from(&self.entries).write_to(&self.path, &self.password).is_ok() 
+        if self.changed_on_disk().is_some() {
+            self.mode = AppMode::ExternalChange(false);
+            return;
+        }
+        self.overwrite();
     }
-
-    fn create_file(&mut self, path: &str) -> io::Result<bool> {
-        loop {
-            self.terminal.draw(|f| {
-                let buf = f.buffer_mut();
-                let area = buf.area;
-                create_file(area, buf, path)
-            })?;
-            if event::poll(std::time::Duration::from_millis(16))? {
-                if let Ok(Event::Key(k)) = read() {
-                    if KeyEvent::new(KeyCode::Char('y'), KeyModifiers::NONE) == k {
-                        break Ok(true);
-                    } else if KeyEvent::new(KeyCode::Char('n'), KeyModifiers::NONE) == k {
-                        break Ok(false);
-                    }
+    /// Make sure no other instance is editing the diary before it is edited here.
+    ///
+    /// Returns false and switches to [`AppMode::LockHeld`] if someone else holds the lock.
+    fn acquire_write_lock(&mut self) -> bool {
+        if self.read_only || self.write_lock.is_some() {
+            return true;
+        }
+        match self.storage.lock() {
+            Ok(lock) => self.write_lock = lock,
+            Err(LockError::Held(holder)) => {
+                self.mode = AppMode::LockHeld(holder);
+                return false;
+            }
+            // Without a lock file (e.g. a read-only directory) there is nothing to race on.
+            Err(LockError::Io(_)) => (),
+        }
+        true
+    }
+    /// Switch between viewing and editing. Unsaved changes are saved before switching to
+    /// viewing, and the write lock is only held while editing.
+    fn toggle_read_only(&mut self) {
+        if self.read_only {
+            self.read_only = false;
+        } else {
+            if !self.saved {
+                self.save();
+                if !self.saved {
+                    return;
                 }
             }
+            self.read_only = true;
+            self.write_lock = None;
         }
     }
-    fn new_password(&mut self) -> io::Result<()> {
-        let mut w = PasswordForm::new();
-        loop {
-            self.terminal.draw(|f| {
-                let buf = f.buffer_mut();
-                password_form_ui(&w, buf)
-            })?;
-            if let Ok(Event::Key(k)) = read() {
-                if let Some(pwd) = w.input(k) {
-                    self.password = pwd;
-                    break;
+    /// The current state of the file if it differs from what was loaded or last saved.
+    fn changed_on_disk(&mut self) -> Option<FileStamp> {
+        self.storage.changed(self.stamp.as_mut()?).ok().flatten()
+    }
+    /// The diary as currently edited, including trash and history.
+    fn to_diary(&self) -> Diary {
+        let mut diary = Diary::from(&self.entries);
+        diary.trash.clone_from(&self.trash);
+        diary.history.clone_from(&self.history);
+        diary
+    }
+    /// A key for a new diary, from the password and keyfile, and shared with the identity.
+    ///
+    /// Without a password or keyfile only the identity unlocks it, so no password slot is kept
+    /// that an empty password would open.
+    fn new_key(&self) -> Result<DiaryKey, DiaryFromFileError> {
+        let mut key = DiaryKey::new(&self.password, self.kdf, self.keyfile.as_ref())?;
+        if let Some(identity) = &self.identity {
+            key.add_recipient(identity.recipient())?;
+            if self.password.is_empty() && self.keyfile.is_none() {
+                key.remove_password()?;
+            }
+        }
+        Ok(key)
+    }
+    /// What unlocks a new diary if the user chooses no password.
+    fn unlocked_by(&self) -> Option<&'static str> {
+        match (self.identity.is_some(), self.keyfile.is_some()) {
+            (true, true) => Some("your identity or the keyfile"),
+            (true, false) => Some("only your identity"),
+            (false, true) => Some("only the keyfile"),
+            (false, false) => None,
+        }
+    }
+    /// Decrypt the stored diary with the identity if there is one, or else `password` (by
+    /// default `self.password`) and the keyfile.
+    fn read_from_disk(
+        &mut self,
+        password: Option<&str>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+        let credentials = match &self.identity {
+            Some(identity) => Credentials::Identity(identity),
+            None => {
+                Credentials::Password(password.unwrap_or(&self.password), self.keyfile.as_ref())
+            }
+        };
+        self.storage.load(credentials)
+    }
+    /// Write the diary regardless of what is on disk now.
+    fn overwrite(&mut self) {
+        let mut diary = self.to_diary();
+        diary.record_revisions(&self.saved_entries, self.max_revisions);
+        if self.key.is_none() {
+            self.key = self.new_key().ok();
+        }
+        let res = match &self.key {
+            Some(key) => self.storage.save(&diary, key),
+            None => Err(DiaryFromFileError::NoWayToUnlock),
+        };
+        self.saved = res.is_ok();
+        self.notice = res.err().map(|e| format!("Not saved: {e}"));
+        if self.saved {
+            self.clear_journal();
+            self.stamp = self.storage.metadata().ok();
+            self.reported_change = None;
+            std::mem::swap(&mut self.history, &mut diary.history);
+            std::mem::swap(&mut self.saved_entries, &mut diary.entries);
+            if let Some(git) = &self.git {
+                // The diary itself is safely on disk; a failed commit is picked up by the next one.
+                if let Err(e) = git.commit(self.storage.name(), self.date) {
+                    self.notice = Some(format!("Saved, but not committed: {e}"));
                 }
             }
         }
+    }
+    /// Replace the open entries with the contents of `diary`.
+    pub(crate) fn load_diary(&mut self, mut diary: Diary) {
+        if let Some(retention) = self.trash_retention {
+            diary.purge_trash(retention);
+        }
+        self.trash = std::mem::take(&mut diary.trash);
+        self.history = std::mem::take(&mut diary.history);
+        self.saved_entries = diary.entries.clone();
+        self.entries = HashMap::from(diary);
+        self.entries
+            .entry(self.date)
+            .or_insert(Self::input_area(self.date, None));
+        self.entries
+            .iter_mut()
+            .for_each(|(date, input)| Self::setup_input_area(input, *date));
+        self.stamp = self.storage.metadata().ok();
+        self.reported_change = None;
+    }
+    /// The unsaved changes to the entries.
+    fn snapshot(&self) -> Snapshot {
+        let changed = self
+            .entries
+            .iter()
+            .map(|(date, ta)| (*date, ta.lines().join("\n")))
+            .filter(|(date, text)| match self.saved_entries.get(date) {
+                Some(saved) => saved != text,
+                None => !text.trim().is_empty(),
+            })
+            .collect();
+        let removed = self
+            .saved_entries
+            .keys()
+            .filter(|date| !self.entries.contains_key(date))
+            .copied()
+            .collect();
+        Snapshot {
+            changed,
+            removed,
+            taken: chrono::Utc::now(),
+        }
+    }
+    /// Record the unsaved changes in the journal if they changed since they were last recorded.
+    fn write_journal(&mut self) {
+        if self.saved || self.read_only {
+            return;
+        }
+        let snapshot = self.snapshot();
+        let Ok(json) = serde_json::to_vec(&(&snapshot.changed, &snapshot.removed)) else {
+            return;
+        };
+        let digest = Some(watch::hash(&secret::Zeroizing::new(json)));
+        if snapshot.is_empty() || digest == self.journaled {
+            return;
+        }
+        if self.key.is_none() {
+            self.key = self.new_key().ok();
+        }
+        let (Some(journal), Some(key)) = (self.storage.journal(), &self.key) else {
+            return;
+        };
+        match journal.write(key, &snapshot) {
+            Ok(()) => self.journaled = digest,
+            Err(e) => self.notice = Some(format!("Journaling is off: {e}")),
+        }
+    }
+    fn clear_journal(&mut self) {
+        if let Some(journal) = self.storage.journal() {
+            let _ = journal.clear();
+        }
+        self.journaled = None;
+    }
+    /// Offer to apply the changes an earlier session left unsaved in the journal.
+    fn recover_journal(&mut self) {
+        let (Some(journal), Some(key)) = (self.storage.journal(), &self.key) else {
+            return;
+        };
+        if let Ok(Some(snapshot)) = journal.replay(key) {
+            if !snapshot.is_empty() {
+                self.mode = AppMode::RecoverJournal(snapshot);
+            }
+        }
+    }
+    fn apply_journal(&mut self, snapshot: Snapshot) {
+        // The journal is kept until these are saved, in case this session crashes too.
+        for (date, text) in &snapshot.changed {
+            if let Some(old) = self
+                .entries
+                .insert(*date, Self::input_area(*date, Some(text)))
+            {
+                wipe(old);
+            }
+        }
+        for date in &snapshot.removed {
+            self.trash_entry(*date);
+        }
+        self.entries
+            .entry(self.date)
+            .or_insert_with(|| Self::input_area(self.date, None));
+        self.saved = false;
+    }
+    /// Combine the diary on disk with the unsaved edits, using the last loaded or saved
+    /// state as the common base.
+    fn merge_from_disk(&mut self) -> Result<(), DiaryFromFileError> {
+        let (theirs, key) = self.read_from_disk(None)?;
+        // Saved with the keys on disk from now on, so a password changed there is not undone.
+        if !self.downgrades(&key) {
+            self.key = Some(key);
+        }
+        let mut base = Diary::new();
+        base.entries.clone_from(&self.saved_entries);
+        let ours = self.to_diary();
+        let merge = Diary::merge(Some(&base), &ours, &theirs, ("this session", "on disk"));
+        self.load_diary(merge.diary);
+        // Revisions on the next save are relative to what is on disk now.
+        self.saved_entries.clone_from(&theirs.entries);
+        self.saved = false;
         Ok(())
     }
-    /// Asks the user if new file 'path' is to be created and set password as well as default contents of the file.
-    /// Returns true if user chose to create file
-    fn new_file(&mut self, path: &str) -> io::Result<bool> {
-        if self.create_file(path)? {
-            File::create(path)?;
-            self.path = path.to_owned();
-            self.new_password()?;
-            self.entries = HashMap::from([(self.date, Self::input_area(self.date, None))]);
-            Ok(true)
+    /// Take in the diary that changed on disk, asking for its password if that changed too.
+    fn rejoin(&mut self, rejoin: Rejoin) {
+        let res = match rejoin {
+            Rejoin::Reload => self.reload().map(|_| {
+                self.saved = true;
+                self.clear_journal();
+            }),
+            Rejoin::Merge => self.merge_from_disk(),
+        };
+        self.mode = match res {
+            Ok(()) => AppMode::Edit,
+            Err(DiaryFromFileError::WrongPassword) => AppMode::Reauthenticate(
+                Prompt::new(" Changed on disk: enter its password "),
+                rejoin,
+            ),
+            Err(DiaryFromFileError::NotEncrypted) => AppMode::ExternalChange(true),
+            Err(e) => {
+                let action = match rejoin {
+                    Rejoin::Reload => "Not reloaded",
+                    Rejoin::Merge => "Not merged",
+                };
+                self.notice = Some(format!("{action}: {e}"));
+                AppMode::Edit
+            }
+        };
+    }
+    /// Move the entry for `date` to the trash.
+    fn trash_entry(&mut self, date: Date) {
+        if let Some(ta) = self.entries.remove(&date) {
+            let text = ta.lines().join("\n");
+            wipe(ta);
+            if !text.trim().is_empty() {
+                self.trash.push(TrashedEntry::new(date, text));
+                self.last_deleted = Some(date);
+            }
+            self.saved = false;
+        }
+    }
+    /// Take the trashed entry at `index` back into the diary.
+    ///
+    /// If an entry was written for the same date in the meantime, the restored text is appended to it.
+    fn restore(&mut self, index: usize) {
+        let mut entry = self.trash.remove(index);
+        let ta = self
+            .entries
+            .entry(entry.date)
+            .or_insert_with(|| Self::input_area(entry.date, None));
+        if ta.is_empty() {
+            wipe(std::mem::replace(
+                ta,
+                Self::input_area(entry.date, Some(&entry.text)),
+            ));
         } else {
-            Ok(false)
+            ta.move_cursor(tui_textarea::CursorMove::Bottom);
+            ta.move_cursor(tui_textarea::CursorMove::End);
+            ta.insert_newline();
+            ta.insert_newline();
+            ta.insert_str(&entry.text);
+        }
+        entry.zeroize();
+        self.date = entry.date;
+        if self.last_deleted == Some(entry.date) {
+            self.last_deleted = None;
         }
+        self.saved = false;
     }
-    fn edit_view(&mut self) -> io::Result<()> {
-        loop {
-            self.terminal.draw(|f: &mut Frame| {
-                let buf = f.buffer_mut();
-                editor_ui(buf, &self.entries[&self.date], &self.entries, &self.date)
-            })?;
-            if event::poll(std::time::Duration::from_millis(16))? {
-                match read() {
-                    Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => match key {
-                        KeyEvent {
-                            code: KeyCode::Char('s'),
-                            modifiers: KeyModifiers::CONTROL,
-                            ..
-                        } => {
-                            self.save();
-                        }
-                        KeyEvent {
-                            code: KeyCode::Char('d'),
-                            modifiers: KeyModifiers::ALT,
-                            ..
-                        } => {
-                            self.mode = AppMode::SetDate;
-                            break;
-                        }
-                        KeyEvent {
-                            code: KeyCode::Char('r'),
-                            modifiers: KeyModifiers::CONTROL,
-                            ..
-                        } => {
-                            self.mode = AppMode::Delete;
-                            break;
-                        }
-                        KeyEvent {
-                            code: KeyCode::Esc, ..
-                        } => {
-                            self.mode = AppMode::Exit;
-                            break;
-                        }
-                        key => self.saved = !self.entries.get_mut(&self.date).unwrap().input(key),
-                    },
-                    Ok(event) => {
-                        self.saved = !self.entries.get_mut(&self.date).unwrap().input(event)
-                    }
-                    _ => (),
-                }
+    /// Replace the text of the current entry with one of its earlier revisions.
+    fn restore_revision(&mut self, index: usize) {
+        if let Some(revision) = self.history.get(&self.date).and_then(|h| h.get(index)) {
+            let ta = Self::input_area(self.date, Some(&revision.text));
+            if let Some(old) = self.entries.insert(self.date, ta) {
+                wipe(old);
             }
+            self.saved = false;
+        }
+    }
+    /// Restore the entry removed by the last delete of this session.
+    fn undo_delete(&mut self) {
+        if let Some(date) = self.last_deleted {
+            if let Some(index) = self.trash.iter().rposition(|t| t.date == date) {
+                self.restore(index);
+            }
+        }
+    }
+    /// Whether the diary is unlocked on this screen, so the lock screen has to hide it. The
+    /// recovery key screens are left alone, as the key is shown only once.
+    fn shows_diary(&self) -> bool {
+        matches!(
+            self.mode,
+            AppMode::Edit
+                | AppMode::AskToSave
+                | AppMode::UnlockNotice(_)
+                | AppMode::SetDate(_)
+                | AppMode::Delete
+                | AppMode::RecoverJournal(_)
+                | AppMode::Trash(_)
+                | AppMode::History(_)
+                | AppMode::ExternalChange(_)
+                | AppMode::LockHeld(_)
+        )
+    }
+    fn idle_timed_out(&self) -> bool {
+        self.lock_after
+            .is_some_and(|after| self.last_activity.elapsed() >= after)
+    }
+    /// Hide the diary behind the lock screen, saving first if there are unsaved changes.
+    fn lock(&mut self) {
+        if !self.saved {
+            self.save();
+            if let AppMode::ExternalChange(_) = self.mode {
+                return;
+            }
+        }
+        self.hide();
+    }
+    /// Lock after [`Self::lock_after`] without input. Nobody is there to answer if saving runs
+    /// into a change on disk, so then the changes stay unsaved and in the journal.
+    fn lock_idle(&mut self) {
+        self.lock();
+        if let AppMode::ExternalChange(_) = self.mode {
+            self.write_journal();
+            self.hide();
+        }
+    }
+    /// Show the lock screen without saving.
+    fn hide(&mut self) {
+        self.mode = AppMode::Locked(Prompt::new(match self.identity {
+            Some(_) => " Locked: enter path of identity file ",
+            None => " Locked: enter password ",
+        }));
+    }
+
+    /// Stop until the shell continues journalr, without leaving the diary on screen.
+    fn suspend(&mut self) -> io::Result<()> {
+        if !self.tty {
+            return Ok(());
+        }
+        let shows_diary = self.shows_diary();
+        if shows_diary {
+            self.write_journal();
+        }
+        self.terminal.clear()?;
+        self.exit()?;
+        signals::stop();
+        self.enter()?;
+        // What the shell drew meanwhile is unknown to ratatui, so draw everything again.
+        self.terminal.clear()?;
+        self.last_activity = Instant::now();
+        if self.lock_on_resume && shows_diary {
+            self.lock();
         }
         Ok(())
     }
-    /// Unlock the file `self.path` using `self.password` and read it into `self.entries`.
+    /// Unlock the diary in `self.storage` using `self.password` and read it into `self.entries`.
     ///
     /// Returns error if:
     /// - Password is wrong
     /// - File cannot be accesed
     pub(crate) fn try_load(&mut self) -> Result<(), DiaryFromFileError> {
-        self.entries = HashMap::from(Diary::read_jrnl(&self.path, &self.password)?);
-        self.entries
-            .entry(self.date)
-            .or_insert(Self::input_area(self.date, None));
-        self.entries
-            .iter_mut()
-            .for_each(|(date, input)| Self::setup_input_area(input, *date));
+        let (diary, key) = self.read_from_disk(None)?;
+        self.load_diary(diary);
+        self.key = Some(key);
         Ok(())
     }
-
-    fn get_password(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut ta = TextArea::default();
-        ta.set_block(Block::bordered().title_top("Enter password"));
-        ta.set_mask_char('*');
-        ta.set_cursor_line_style(Style::default().fg(ratatui::style::Color::Gray));
-        loop {
-            self.terminal.draw(|f| {
-                centered_input_box(&ta, f.buffer_mut());
-            })?;
-            if event::poll(std::time::Duration::from_millis(16))? {
-                if let Ok(Event::Key(k)) = read() {
-                    if KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE) == k {
-                        self.mode = AppMode::Exit;
-                        break;
-                    } else if KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE) == k {
-                        let password = ta.lines().first().map(|x| x.as_str());
-                        if let Some(s) = password {
-                            self.password = s.to_string();
-                            if self.try_load().is_ok() {
-                                self.mode = AppMode::Edit;
-                                break;
-                            } else {
-                                clear(&mut ta);
-                                ta.set_placeholder_text("Wrong Password");
-                                continue;
-                            }
-                        }
-                    }
-                    ta.input(k);
-                }
-            }
+    /// Whether saving with `key` would store this diary as plaintext while it is encrypted;
+    /// only `journalr convert` decrypts a diary.
+    fn downgrades(&self, key: &DiaryKey) -> bool {
+        key.is_plaintext() && self.key.as_ref().is_some_and(|key| !key.is_plaintext())
+    }
+    /// Like [`Self::try_load`] after the file changed on disk, but a diary that was encrypted
+    /// is not switched to plaintext.
+    fn reload(&mut self) -> Result<(), DiaryFromFileError> {
+        let (diary, key) = self.read_from_disk(None)?;
+        if self.downgrades(&key) {
+            return Err(DiaryFromFileError::NotEncrypted);
         }
+        self.load_diary(diary);
+        self.key = Some(key);
         Ok(())
     }
 
-    fn pre_exit(&mut self) -> io::Result<()> {
-        if !self.saved {
-            loop {
-                self.terminal.draw(|f: &mut Frame| {
-                    let buf = f.buffer_mut();
-                    pre_exit_ui(buf);
-                })?;
-                if event::poll(std::time::Duration::from_millis(16))? {
-                    if let Ok(Event::Key(k)) = read() {
-                        if KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE) == k
-                            || KeyEvent::new(KeyCode::Char('n'), KeyModifiers::NONE) == k
-                        {
-                            self.mode = AppMode::Edit;
-                            break;
-                        } else if KeyEvent::new(KeyCode::Char('y'), KeyModifiers::NONE) == k {
-                            self.mode = AppMode::Exit;
-                            break;
-                        }
-                    }
+    /// Placeholder for a password prompt that is refusing attempts until `retry_at`.
+    fn backoff_message(retry_at: Option<Instant>) -> Option<String> {
+        let wait = retry_at?.saturating_duration_since(Instant::now());
+        (!wait.is_zero()).then(|| {
+            format!(
+                "Too many wrong passwords; try again in {} s",
+                wait.as_secs() + 1
+            )
+        })
+    }
+    /// Unlock the diary with `self.password` as given on the command line, keeping to the
+    /// backoff of the password prompt.
+    pub(crate) fn load_with_password(&mut self) -> Result<(), AttemptError> {
+        let (attempts, name) = (self.attempts.clone(), self.storage.name().to_owned());
+        attempts.attempt(&name, || self.try_load())
+    }
+    /// Continue after the diary was opened with `self.password`: ask for a keyfile if it needs
+    /// one, or for the password again if it was wrong.
+    fn password_tried(&mut self, res: Result<(), DiaryFromFileError>) {
+        self.mode = match res {
+            Ok(()) => {
+                let previous = self.attempts.succeeded(self.storage.name()).ok();
+                match previous.and_then(|r| r.summary()) {
+                    Some(summary) => AppMode::UnlockNotice(summary),
+                    None => AppMode::Edit,
                 }
             }
+            Err(e @ (DiaryFromFileError::KeyfileRequired | DiaryFromFileError::WrongKeyfile)) => {
+                AppMode::Keyfile(Self::input_box(" Keyfile ", &e.to_string()), e)
+            }
+            Err(e) => return self.password_failed(e),
+        };
+    }
+    fn password_failed(&mut self, e: DiaryFromFileError) {
+        if let DiaryFromFileError::WrongPassword | DiaryFromFileError::WrongKeyfile = e {
+            let _ = self.attempts.failed(self.storage.name());
+        }
+        let mut prompt = Prompt::new("Enter password");
+        prompt.error = e.to_string();
+        self.mode = AppMode::Password(prompt);
+    }
+    /// Open the diary at `filename` if it needs no password, or else ask for one.
+    fn open_file(&mut self, filename: String) {
+        let mut storage = storage::open(&filename);
+        let ph = match storage.load(Credentials::Nothing) {
+            Ok((diary, key)) => {
+                self.mode = AppMode::Edit;
+                self.storage = storage;
+                self.load_diary(diary);
+                self.key = Some(key);
+                return;
+            }
+            Err(e) => match e {
+                DiaryFromFileError::WrongPassword
+                | DiaryFromFileError::KeyfileRequired
+                | DiaryFromFileError::WrongKeyfile => {
+                    self.storage = storage;
+                    self.mode = AppMode::password();
+                    return;
+                }
+                DiaryFromFileError::InvalidFormat | DiaryFromFileError::NoRecoveryKey => {
+                    "Invalid File Format"
+                }
+                DiaryFromFileError::IdentityRequired => {
+                    "This diary is opened with an identity file (--identity)"
+                }
+                DiaryFromFileError::LegacyFormat
+                | DiaryFromFileError::NotARecipient
+                | DiaryFromFileError::NoWayToUnlock
+                | DiaryFromFileError::ChangedOnDisk
+                | DiaryFromFileError::NotEncrypted
+                | DiaryFromFileError::AlreadyExists => "Cannot open this diary",
+                DiaryFromFileError::OutOfRangeSize => "File too Large",
+                DiaryFromFileError::NotFound => {
+                    self.mode = AppMode::CreateFile(filename);
+                    return;
+                }
+                DiaryFromFileError::NotAccessible => "File Cannot be Accesed",
+            },
+        };
+        self.mode = AppMode::GetFile(Self::input_box("Enter name of File to open", ph));
+    }
+    /// Start the new diary with the password just chosen, and write it right away so that it
+    /// can be unlocked before the first save.
+    fn new_file(&mut self, password: &str) {
+        self.password = Password::new(password, self.mlock);
+        self.load_diary(Diary::new());
+        // The user confirmed they want no password, so store the diary as plaintext.
+        let plaintext =
+            self.password.is_empty() && self.keyfile.is_none() && self.identity.is_none();
+        self.key = match plaintext {
+            true => Some(DiaryKey::Plaintext),
+            false => self.new_key().ok(),
+        };
+        self.overwrite();
+        self.mode = if !self.saved {
+            AppMode::GetFile(Self::input_box(
+                "Enter name of File to open",
+                "File could not be created",
+            ))
+        } else if plaintext {
+            AppMode::Edit
         } else {
-            self.mode = AppMode::Exit;
+            AppMode::OfferRecoveryKey
+        };
+    }
+    /// Give the new diary a recovery key, to be shown once.
+    fn add_recovery_key(&mut self) -> Result<secret::Zeroizing<String>, DiaryFromFileError> {
+        let key = self.key.as_mut().ok_or(DiaryFromFileError::NoWayToUnlock)?;
+        let recovery = key.add_recovery()?;
+        self.overwrite();
+        match self.saved {
+            true => Ok(recovery.encode()),
+            false => Err(DiaryFromFileError::NotAccessible),
+        }
+    }
+
+    /// Draw the current screen, then wait for one event and handle it.
+    pub fn step(&mut self) -> io::Result<()> {
+        self.tick()?;
+        self.draw()?;
+        if let Some(event) = self.events.next(Self::FRAME)? {
+            self.last_activity = Instant::now();
+            self.handle(event)?;
         }
         Ok(())
     }
-    fn set_date_ui(&mut self) -> io::Result<Option<Date>> {
-        let mut uis = DateSelection::new(self.date);
-        loop {
-            self.terminal.draw(|f| {
-                get_date_ui(f.buffer_mut(), &mut uis);
-            })?;
-            if event::poll(std::time::Duration::from_millis(16))? {
-                if let Ok(Event::Key(k)) = read() {
-                    if KeyEvent::new(KeyCode::Char('+'), KeyModifiers::NONE) == k
-                        || KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE) == k
-                        || KeyEvent::new(KeyCode::Up, KeyModifiers::NONE) == k
-                    {
-                        uis.increment_selected();
-                    } else if KeyEvent::new(KeyCode::Char('-'), KeyModifiers::NONE) == k
-                        || KeyEvent::new(KeyCode::Down, KeyModifiers::NONE) == k
-                    {
-                        uis.decrement_selected();
-                    } else if KeyEvent::new(KeyCode::Right, KeyModifiers::NONE) == k {
-                        uis.select_next();
-                    } else if KeyEvent::new(KeyCode::Left, KeyModifiers::NONE) == k {
-                        uis.select_prev();
-                    } else if KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE) == k {
-                        self.mode = AppMode::Edit;
-                        return Ok(Some(uis.date()));
-                    } else if KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE) == k {
-                        self.mode = AppMode::Edit;
-                        return Ok(None);
-                    }
+    /// Whatever is due regardless of input: taking the write lock, looking at the journal, and
+    /// the timers of the editor.
+    fn tick(&mut self) -> io::Result<()> {
+        if let AppMode::Password(prompt)
+        | AppMode::Locked(prompt)
+        | AppMode::Reauthenticate(prompt, _) = &mut self.mode
+        {
+            if prompt.retry_at.is_none() {
+                prompt.retry_at = Some(Instant::now() + self.attempts.wait(self.storage.name()));
+            }
+        }
+        if signals::take_suspend() {
+            self.suspend()?;
+        }
+        if self.shows_diary() && self.idle_timed_out() {
+            self.lock_idle();
+            return Ok(());
+        }
+        if !matches!(self.mode, AppMode::Edit) || !self.acquire_write_lock() {
+            return Ok(());
+        }
+        if !self.journal_checked {
+            self.journal_checked = true;
+            // A read-only session may be looking at a diary someone else is editing.
+            if !self.read_only {
+                self.recover_journal();
+                if !matches!(self.mode, AppMode::Edit) {
+                    return Ok(());
                 }
             }
         }
-    }
-    fn delete(&mut self) -> io::Result<()> {
-        loop {
-            self.terminal
-                .draw(|f| delete_ui(self.date, f.buffer_mut()))?;
-            if event::poll(std::time::Duration::from_millis(16))? {
-                if let Ok(Event::Key(k)) = read() {
-                    if KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE) == k
-                        || KeyEvent::new(KeyCode::Char('n'), KeyModifiers::NONE) == k
-                    {
-                        self.mode = AppMode::Edit;
-                        break;
-                    } else if KeyEvent::new(KeyCode::Char('y'), KeyModifiers::NONE) == k {
-                        self.entries.remove(&self.date);
-                        if let Some(date) = self.set_date_ui()? {
-                            self.date = date;
-                        } else {
-                            self.date = Date::today();
-                        }
-                        self.entries
-                            .entry(self.date)
-                            .or_insert_with(|| Self::input_area(self.date, None));
-                        break;
-                    }
+        if self.last_journal.elapsed() >= Self::JOURNAL_INTERVAL {
+            self.last_journal = Instant::now();
+            self.write_journal();
+        }
+        if self.last_check.elapsed() >= Self::CHECK_INTERVAL {
+            self.last_check = Instant::now();
+            if let Some(stamp) = self.changed_on_disk() {
+                if self.read_only {
+                    // Nothing to lose, so just show what is on disk now.
+                    let _ = self.reload();
+                } else if self.reported_change.as_ref() != Some(&stamp) {
+                    self.reported_change = Some(stamp);
+                    self.mode = AppMode::ExternalChange(false);
                 }
             }
         }
         Ok(())
     }
-    fn get_input(
-        &mut self,
-        title: &str,
-        placeholder: &str,
-        mask: Option<char>,
-    ) -> Result<Option<String>, io::Error> {
-        let mut input_area = TextArea::default();
-        input_area.set_block(Block::bordered().title_top(title));
-        input_area.set_cursor_style(Style::new().bg(ratatui::style::Color::White));
-        input_area.set_placeholder_text(placeholder);
-        if let Some(c) = mask {
-            input_area.set_mask_char(c);
+    fn draw(&mut self) -> io::Result<()> {
+        self.terminal.draw(|f| {
+            let buf = f.buffer_mut();
+            match &mut self.mode {
+                AppMode::Edit => editor_ui(
+                    buf,
+                    &self.entries[&self.date],
+                    &self.entries,
+                    &self.date,
+                    self.read_only,
+                    self.key.as_ref().is_some_and(DiaryKey::is_plaintext),
+                    self.notice.as_deref(),
+                ),
+                AppMode::AskToSave => pre_exit_ui(buf),
+                AppMode::Password(prompt) | AppMode::Reauthenticate(prompt, _) => {
+                    let placeholder = Self::backoff_message(prompt.retry_at);
+                    prompt
+                        .ta
+                        .set_placeholder_text(placeholder.unwrap_or(prompt.error.clone()));
+                    centered_input_box(&prompt.ta, buf);
+                }
+                AppMode::Locked(prompt) => {
+                    let placeholder = Self::backoff_message(prompt.retry_at);
+                    prompt
+                        .ta
+                        .set_placeholder_text(placeholder.unwrap_or(prompt.error.clone()));
+                    lock_ui(&prompt.ta, buf);
+                }
+                AppMode::Keyfile(ta, _) | AppMode::GetFile(ta) => centered_input_box(ta, buf),
+                AppMode::UnlockNotice(summary) => unlock_notice_ui(buf, summary),
+                AppMode::Exit => (),
+                AppMode::SetDate(uis) => get_date_ui(buf, uis),
+                AppMode::Delete => delete_ui(self.date, buf),
+                AppMode::CreateFile(path) => create_file(buf.area, buf, path),
+                AppMode::NewPassword(form) => password_form_ui(form, buf),
+                AppMode::OfferRecoveryKey => offer_recovery_key_ui(buf),
+                AppMode::RecoveryKey(recovery) => recovery_key_ui(buf, recovery),
+                AppMode::RecoverJournal(snapshot) => {
+                    let taken = snapshot
+                        .taken
+                        .with_timezone(&chrono::Local)
+                        .format("%d-%m-%Y %H:%M")
+                        .to_string();
+                    let count = snapshot.changed.len() + snapshot.removed.len();
+                    recover_journal_ui(buf, &taken, count)
+                }
+                AppMode::Trash(selected) => trash_ui(buf, &self.trash, *selected),
+                AppMode::History(selected) => {
+                    let revisions = self.history.get(&self.date).map_or(&[][..], Vec::as_slice);
+                    history_ui(
+                        buf,
+                        self.date,
+                        revisions,
+                        &self.entries[&self.date].lines().join("\n"),
+                        *selected,
+                    )
+                }
+                AppMode::ExternalChange(refused) => {
+                    external_change_ui(self.storage.name(), *refused, buf)
+                }
+                AppMode::LockHeld(holder) => {
+                    lock_held_ui(self.storage.name(), holder.as_ref(), buf)
+                }
+            }
+        })?;
+        Ok(())
+    }
+    /// Pass `event` to the current screen, which decides the next one.
+    fn handle(&mut self, event: Event) -> io::Result<()> {
+        let key = |code| Event::Key(KeyEvent::new(code, KeyModifiers::NONE));
+        // Suspending works on every screen, like it does for SIGTSTP.
+        #[cfg(unix)]
+        if let Event::Key(KeyEvent {
+            code: KeyCode::Char('z'),
+            modifiers: KeyModifiers::CONTROL,
+            kind,
+            ..
+        }) = event
+        {
+            if kind != KeyEventKind::Release {
+                return self.suspend();
+            }
         }
-        loop {
-            self.terminal
-                .draw(|f| centered_input_box(&input_area, f.buffer_mut()))?;
-            if event::poll(Duration::from_millis(20))? {
-                if let Ok(event) = read() {
-                    if let Event::Key(key) = event {
-                        if KeyEventKind::Release != key.kind {
-                            match key.code {
-                                KeyCode::Enter => {
-                                    return Ok(Some(
-                                        input_area
-                                            .lines()
-                                            .first()
-                                            .map(|f| f.to_owned())
-                                            .unwrap_or_default(),
-                                    ))
-                                }
-                                KeyCode::Esc => return Ok(None),
-                                _ => (),
-                            };
+        // Each screen puts itself back unless it moves on to another one.
+        match std::mem::take(&mut self.mode) {
+            AppMode::Edit => self.edit_input(event)?,
+            AppMode::AskToSave => {
+                self.mode = if event == key(KeyCode::Esc) || event == key(KeyCode::Char('n')) {
+                    AppMode::Edit
+                } else if event == key(KeyCode::Char('y')) {
+                    self.clear_journal();
+                    AppMode::Exit
+                } else {
+                    AppMode::AskToSave
+                }
+            }
+            AppMode::Password(mut prompt) => {
+                if event == key(KeyCode::Esc) {
+                    self.mode = AppMode::Exit;
+                } else if event == key(KeyCode::Enter) {
+                    if prompt.waiting() {
+                        self.mode = AppMode::Password(prompt);
+                        return Ok(());
+                    }
+                    let input = prompt.take();
+                    let password = input.first().map(String::as_str).unwrap_or_default();
+                    self.password = Password::new(password, self.mlock);
+                    let res = self.try_load();
+                    self.password_tried(res);
+                } else {
+                    if let Event::Key(k) = event {
+                        prompt.ta.input(k);
+                    }
+                    self.mode = AppMode::Password(prompt);
+                }
+            }
+            AppMode::Keyfile(mut ta, error) => match event {
+                Event::Key(k) if k.kind != KeyEventKind::Release && k.code == KeyCode::Enter => {
+                    let path = ta.lines().first().cloned().unwrap_or_default();
+                    match Keyfile::read(&path) {
+                        Ok(keyfile) => {
+                            self.keyfile = Some(keyfile);
+                            let res = self.try_load();
+                            self.password_tried(res);
+                        }
+                        Err(e) => {
+                            let reason = format!("{path}: {e}");
+                            self.mode =
+                                AppMode::Keyfile(Self::input_box(" Keyfile ", &reason), error);
                         }
                     }
-                    input_area.input(event);
+                }
+                Event::Key(k) if k.kind != KeyEventKind::Release && k.code == KeyCode::Esc => {
+                    self.password_failed(error)
+                }
+                event => {
+                    ta.input(event);
+                    self.mode = AppMode::Keyfile(ta, error);
+                }
+            },
+            AppMode::UnlockNotice(summary) => {
+                self.mode = match event {
+                    Event::Key(k) if k.kind != KeyEventKind::Release => AppMode::Edit,
+                    _ => AppMode::UnlockNotice(summary),
                 }
             }
-        }
-    }
-    fn open_file_rw(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut ph = "";
-        loop {
-            let filename = match self.get_input("Enter name of File to open", ph, None)? {
-                Some(file) => file,
-                None => {
+            AppMode::Exit => self.mode = AppMode::Exit,
+            AppMode::SetDate(mut uis) => {
+                if event == key(KeyCode::Char('+'))
+                    || event == key(KeyCode::Char(' '))
+                    || event == key(KeyCode::Up)
+                {
+                    uis.increment_selected();
+                } else if event == key(KeyCode::Char('-')) || event == key(KeyCode::Down) {
+                    uis.decrement_selected();
+                } else if event == key(KeyCode::Right) {
+                    uis.select_next();
+                } else if event == key(KeyCode::Left) {
+                    uis.select_prev();
+                } else if event == key(KeyCode::Enter) || event == key(KeyCode::Esc) {
+                    if event == key(KeyCode::Enter) {
+                        self.date = uis.date();
+                    } else if !self.entries.contains_key(&self.date) {
+                        // The entry that was shown was just deleted.
+                        self.date = Date::today();
+                    }
+                    self.entries
+                        .entry(self.date)
+                        .or_insert_with(|| Self::input_area(self.date, None));
+                    self.mode = AppMode::Edit;
+                    return Ok(());
+                }
+                self.mode = AppMode::SetDate(uis);
+            }
+            AppMode::Delete => {
+                self.mode = if event == key(KeyCode::Esc) || event == key(KeyCode::Char('n')) {
+                    AppMode::Edit
+                } else if event == key(KeyCode::Char('y')) {
+                    self.trash_entry(self.date);
+                    AppMode::SetDate(DateSelection::new(self.date))
+                } else {
+                    AppMode::Delete
+                }
+            }
+            AppMode::GetFile(mut ta) => match event {
+                Event::Key(k) if k.kind != KeyEventKind::Release && k.code == KeyCode::Enter => {
+                    self.open_file(ta.lines().first().cloned().unwrap_or_default())
+                }
+                Event::Key(k) if k.kind != KeyEventKind::Release && k.code == KeyCode::Esc => {
+                    self.mode = AppMode::Exit
+                }
+                event => {
+                    ta.input(event);
+                    self.mode = AppMode::GetFile(ta);
+                }
+            },
+            AppMode::CreateFile(path) => {
+                self.mode = if event == key(KeyCode::Char('y')) {
+                    self.storage = storage::open(&path);
+                    match self.storage.create() {
+                        Ok(()) => AppMode::NewPassword(Box::new(
+                            PasswordForm::new(self.password_policy).unlocked_by(self.unlocked_by()),
+                        )),
+                        Err(_) => AppMode::GetFile(Self::input_box(
+                            "Enter name of File to open",
+                            "File could not be created",
+                        )),
+                    }
+                } else if event == key(KeyCode::Char('n')) {
+                    AppMode::Exit
+                } else {
+                    AppMode::CreateFile(path)
+                }
+            }
+            AppMode::NewPassword(mut form) => {
+                let password = match event {
+                    Event::Key(k) => form.input(k),
+                    _ => None,
+                };
+                match password {
+                    Some(password) => self.new_file(&password),
+                    None => self.mode = AppMode::NewPassword(form),
+                }
+            }
+            AppMode::OfferRecoveryKey => {
+                self.mode = if event == key(KeyCode::Char('y')) {
+                    match self.add_recovery_key() {
+                        Ok(recovery) => AppMode::RecoveryKey(recovery),
+                        Err(_) => AppMode::GetFile(Self::input_box(
+                            "Enter name of File to open",
+                            "File could not be created",
+                        )),
+                    }
+                } else if event == key(KeyCode::Char('n')) {
+                    AppMode::Edit
+                } else {
+                    AppMode::OfferRecoveryKey
+                }
+            }
+            AppMode::RecoveryKey(recovery) => {
+                if event == key(KeyCode::Enter) {
+                    // Don't leave the key in the terminal's scrollback or on screen behind the editor.
+                    self.terminal.clear()?;
+                    self.mode = AppMode::Edit;
+                } else {
+                    self.mode = AppMode::RecoveryKey(recovery);
+                }
+            }
+            AppMode::RecoverJournal(snapshot) => {
+                if event == key(KeyCode::Char('y')) {
+                    self.apply_journal(snapshot);
+                } else if event == key(KeyCode::Char('n')) {
+                    self.clear_journal();
+                } else {
+                    self.mode = AppMode::RecoverJournal(snapshot);
+                }
+            }
+            AppMode::Locked(mut prompt) => {
+                if event == key(KeyCode::Esc) && self.saved {
                     self.mode = AppMode::Exit;
-                    break;
+                } else if event == key(KeyCode::Enter) {
+                    if !prompt.waiting() {
+                        self.unlock(&mut prompt);
+                    }
+                    if !matches!(self.mode, AppMode::Edit) {
+                        self.mode = AppMode::Locked(prompt);
+                    }
+                } else {
+                    if let Event::Key(k) = event {
+                        prompt.ta.input(k);
+                    }
+                    self.mode = AppMode::Locked(prompt);
+                }
+            }
+            AppMode::Trash(selected) => {
+                self.mode = if event == key(KeyCode::Esc) {
+                    AppMode::Edit
+                } else if event == key(KeyCode::Up) {
+                    AppMode::Trash(selected.saturating_sub(1))
+                } else if event == key(KeyCode::Down) {
+                    AppMode::Trash((selected + 1).min(self.trash.len().saturating_sub(1)))
+                } else if event == key(KeyCode::Enter) && !self.trash.is_empty() && !self.read_only
+                {
+                    // The trash is shown newest first.
+                    self.restore(self.trash.len() - 1 - selected);
+                    AppMode::Edit
+                } else {
+                    AppMode::Trash(selected)
+                }
+            }
+            AppMode::History(selected) => {
+                let count = self.history.get(&self.date).map_or(0, Vec::len);
+                self.mode = if event == key(KeyCode::Esc) {
+                    AppMode::Edit
+                } else if event == key(KeyCode::Up) {
+                    AppMode::History(selected.saturating_sub(1))
+                } else if event == key(KeyCode::Down) {
+                    AppMode::History((selected + 1).min(count.saturating_sub(1)))
+                } else if event == key(KeyCode::Enter) && count > 0 && !self.read_only {
+                    // Revisions are shown newest first.
+                    self.restore_revision(count - 1 - selected);
+                    AppMode::Edit
+                } else {
+                    AppMode::History(selected)
                 }
-            };
-            match Diary::read_jrnl(&filename, "") {
-                Ok(entries) => {
+            }
+            AppMode::ExternalChange(refused) => {
+                if event == key(KeyCode::Char('r')) {
+                    self.rejoin(Rejoin::Reload);
+                } else if event == key(KeyCode::Char('m')) {
+                    self.rejoin(Rejoin::Merge);
+                } else if event == key(KeyCode::Char('o')) {
+                    self.overwrite();
                     self.mode = AppMode::Edit;
-                    self.entries = HashMap::from(entries);
-                    self.entries
-                        .entry(Date::today())
-                        .or_insert(Self::input_area(Date::today(), None));
-                    self.entries
-                        .iter_mut()
-                        .for_each(|(date, input)| Self::setup_input_area(input, *date));
-                    break;
-                }
-                Err(e) => match e {
-                    DiaryFromFileError::WrongPassword => {
-                        self.path = filename;
-                        self.mode = AppMode::Password;
-                        break;
+                } else if event == key(KeyCode::Esc) {
+                    self.reported_change = self.changed_on_disk();
+                    self.mode = AppMode::Edit;
+                } else {
+                    self.mode = AppMode::ExternalChange(refused);
+                }
+            }
+            AppMode::Reauthenticate(mut prompt, rejoin) => {
+                if event == key(KeyCode::Esc) {
+                    self.mode = AppMode::ExternalChange(false);
+                } else if event == key(KeyCode::Enter) && !prompt.waiting() {
+                    let input = prompt.take();
+                    let password = input.first().map(String::as_str).unwrap_or_default();
+                    self.password = Password::new(password, self.mlock);
+                    self.rejoin(rejoin);
+                    if let AppMode::Reauthenticate(prompt, _) = &mut self.mode {
+                        let _ = self.attempts.failed(self.storage.name());
+                        prompt.error = DiaryFromFileError::WrongPassword.to_string();
+                    } else {
+                        let _ = self.attempts.succeeded(self.storage.name());
                     }
-                    DiaryFromFileError::InvalidFormat => {
-                        ph = "Invalid File Format";
+                } else {
+                    if let Event::Key(k) = event {
+                        prompt.ta.input(k);
                     }
-                    DiaryFromFileError::OutOfRangeSize => {
-                        ph = "File too Large";
+                    self.mode = AppMode::Reauthenticate(prompt, rejoin);
+                }
+            }
+            AppMode::LockHeld(holder) => {
+                self.mode = if event == key(KeyCode::Char('r')) {
+                    self.read_only = true;
+                    AppMode::Edit
+                } else if event == key(KeyCode::Char('b')) {
+                    self.write_lock = self.storage.force_lock().ok().flatten();
+                    AppMode::Edit
+                } else if event == key(KeyCode::Esc) {
+                    AppMode::Exit
+                } else {
+                    AppMode::LockHeld(holder)
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Check what was typed into the lock screen against the diary on disk, rather than
+    /// `self.password`: the password, or the path to the identity file the diary was opened with.
+    fn unlock(&mut self, prompt: &mut Prompt<'_>) {
+        let input = prompt.take();
+        let input = input.first().map(String::as_str).unwrap_or_default();
+        let unlocked = match self.identity {
+            Some(_) => Identity::read(input).is_ok_and(|identity| {
+                let credentials = Credentials::Identity(&identity);
+                self.storage.load(credentials).is_ok()
+            }),
+            None => self.read_from_disk(Some(input)).is_ok(),
+        };
+        if unlocked {
+            let _ = self.attempts.succeeded(self.storage.name());
+            self.last_activity = Instant::now();
+            self.mode = AppMode::Edit;
+            return;
+        }
+        let _ = self.attempts.failed(self.storage.name());
+        prompt.retry_at = None;
+        prompt.error = match self.identity {
+            Some(_) => "Wrong Identity",
+            None => "Wrong Password",
+        }
+        .to_owned();
+    }
+    fn edit_input(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Key(key) if key.kind != KeyEventKind::Release => match key {
+                KeyEvent {
+                    code: KeyCode::Char('s'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                } if !self.read_only => self.save(),
+                KeyEvent {
+                    code: KeyCode::Char('l'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                } => self.lock(),
+                KeyEvent {
+                    code: KeyCode::Char('d'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => self.mode = AppMode::SetDate(DateSelection::new(self.date)),
+                KeyEvent {
+                    code: KeyCode::Char('r'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                } if !self.read_only => self.mode = AppMode::Delete,
+                KeyEvent {
+                    code: KeyCode::Char('u'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } if !self.read_only => self.undo_delete(),
+                KeyEvent {
+                    code: KeyCode::Char('h'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => self.mode = AppMode::History(0),
+                KeyEvent {
+                    code: KeyCode::Char('t'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => self.mode = AppMode::Trash(0),
+                KeyEvent {
+                    code: KeyCode::Char('r'),
+                    modifiers: KeyModifiers::ALT,
+                    ..
+                } => self.toggle_read_only(),
+                KeyEvent {
+                    code: KeyCode::Esc, ..
+                } => {
+                    self.mode = if self.read_only || self.saved {
+                        AppMode::Exit
+                    } else {
+                        AppMode::AskToSave
                     }
-                    DiaryFromFileError::NotFound => match self.new_file(&filename) {
-                        Ok(created) => {
-                            if created {
-                                self.path = filename;
-                                self.mode = AppMode::Edit;
-                                break;
-                            } else {
-                                self.mode = AppMode::Exit;
-                                break;
-                            }
-                        }
-                        Err(_) => {
-                            ph = "File could not be created";
-                        }
-                    },
-                    DiaryFromFileError::NotAccessible => {
-                        ph = "File Cannot be Accesed";
+                }
+                key if self.read_only => {
+                    if is_navigation(&key) {
+                        self.entries.get_mut(&self.date).unwrap().input(key);
                     }
-                },
-            };
+                }
+                key => self.saved &= !self.entries.get_mut(&self.date).unwrap().input(key),
+            },
+            Event::Paste(_) if self.read_only => (),
+            event => self.saved &= !self.entries.get_mut(&self.date).unwrap().input(event),
+        }
+        Ok(())
+    }
+    fn enter(&mut self) -> io::Result<()> {
+        if self.tty {
+            enable_raw_mode()?;
+            stderr().execute(EnterAlternateScreen)?;
         }
         Ok(())
     }
     pub fn exit(&mut self) -> io::Result<()> {
-        disable_raw_mode()?;
-        stderr().execute(LeaveAlternateScreen)?;
+        if self.tty {
+            disable_raw_mode()?;
+            stderr().execute(LeaveAlternateScreen)?;
+        }
         Ok(())
     }
     pub fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
-        enable_raw_mode()?;
-        stderr().execute(EnterAlternateScreen)?;
+        secret::disable_core_dumps();
+        if self.tty {
+            signals::install();
+        }
+        self.enter()?;
+        let res = self.drive();
+        self.exit()?;
+        res
+    }
+    /// Handle events until the user quits, a termination signal arrives or the events run out.
+    pub fn drive(&mut self) -> Result<(), Box<dyn Error>> {
         self.entries
             .entry(self.date)
             .or_insert(Self::input_area(self.date, None));
         loop {
-            match self.mode {
-                AppMode::Edit => self.edit_view()?,
-                AppMode::Password => self.get_password()?,
-                AppMode::AskToSave => self.pre_exit()?,
-                AppMode::SetDate => {
-                    if let Some(date) = self.set_date_ui()? {
-                        self.entries
-                            .entry(date)
-                            .or_insert_with(|| Self::input_area(date, None));
-                        self.date = date;
-                    }
-                }
-                AppMode::Exit => return Ok(self.exit()?),
-                AppMode::Delete => self.delete()?,
-                AppMode::GetFile => self.open_file_rw()?,
+            if let Some(signal) = signals::received() {
+                self.write_journal();
+                let unsaved = match self.journaled {
+                    Some(_) => "; unsaved changes will be offered when the diary is next opened",
+                    None => "",
+                };
+                return Err(format!("Stopped by signal {signal}{unsaved}").into());
+            }
+            if let AppMode::Exit = self.mode {
+                return Ok(());
+            }
+            if self.events.is_exhausted() {
+                // Leave the outcome of the last event on screen.
+                self.tick()?;
+                return Ok(self.draw()?);
             }
+            self.step()?;
         }
     }
 }
-impl Drop for App<'_> {
+/// Keys that move around an entry without changing it.
+fn is_navigation(key: &KeyEvent) -> bool {
+    matches!(
+        key.code,
+        KeyCode::Up
+            | KeyCode::Down
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::PageUp
+            | KeyCode::PageDown
+            | KeyCode::Home
+            | KeyCode::End
+    )
+}
+impl<B: Backend> Drop for App<'_, B> {
     fn drop(&mut self) {
+        if std::thread::panicking() {
+            // Last chance to keep what was typed since the journal was last written.
+            self.write_journal();
+        }
         let _ = self.exit();
+        self.entries.drain().for_each(|(_, ta)| wipe(ta));
+        self.saved_entries.values_mut().for_each(Zeroize::zeroize);
+        self.trash.iter_mut().for_each(Zeroize::zeroize);
+        self.history
+            .values_mut()
+            .flatten()
+            .for_each(Zeroize::zeroize);
     }
 }
 
 #[test]
-fn test() {
-    let app = App::new().unwrap();
-    // app.mode = AppMode::NewFile;
-    app.run().unwrap()
+fn scripted_keys_edit_save_and_quit() {
+    use crate::{events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let storage = MemoryStorage::new();
+    let events = ScriptedEvents::default()
+        .type_text("dear diary")
+        .key(KeyCode::Char('s'), KeyModifiers::CONTROL)
+        .key(KeyCode::Char('r'), KeyModifiers::CONTROL)
+        .key(KeyCode::Char('n'), KeyModifiers::NONE)
+        .key(KeyCode::Esc, KeyModifiers::NONE);
+    let mut app = App::with_backend(TestBackend::new(80, 24), events).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.key = Some(DiaryKey::Plaintext);
+    app.mode = AppMode::Edit;
+    app.drive().unwrap();
+    assert!(matches!(app.mode, AppMode::Exit));
+    let screen = app.terminal.backend().buffer().content();
+    let screen: String = screen.iter().map(|cell| cell.symbol()).collect();
+    assert!(screen.contains("dear diary"));
+    let saved = String::from_utf8(storage.bytes().unwrap()).unwrap();
+    assert!(saved.contains("dear diary"));
+}
+
+#[test]
+fn new_diary_is_written_before_the_first_save() {
+    use crate::{events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let mut storage = MemoryStorage::new();
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    app.new_file("pw");
+    assert!(matches!(app.mode, AppMode::OfferRecoveryKey));
+    let recovery = crate::crypto::RecoveryKey::parse(&app.add_recovery_key().unwrap()).unwrap();
+    drop(app);
+    assert!(storage.load(Credentials::Password("pw", None)).is_ok());
+    let bytes = storage.bytes().unwrap();
+    assert!(DiaryKey::recover(&mut bytes.as_slice(), &recovery).is_ok());
+}
+
+#[test]
+fn a_diary_for_an_identity_has_no_empty_password() {
+    use crate::{crypto::Identity, events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let mut storage = MemoryStorage::new();
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    app.identity = Some(Identity::generate());
+    app.new_file("");
+    assert!(!app.key.as_ref().unwrap().is_plaintext());
+    assert!(storage.load(Credentials::Password("", None)).is_err());
+    let identity = app.identity.as_ref().unwrap();
+    assert!(storage.load(Credentials::Identity(identity)).is_ok());
+}
+
+#[test]
+fn journaling_that_fails_is_reported() {
+    use crate::events::ScriptedEvents;
+    use ratatui::backend::TestBackend;
+    let path = std::env::temp_dir().join(format!("journalr-legacy-{}.jrnl", std::process::id()));
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(FileStorage::new(path.to_string_lossy()));
+    app.key = Some(DiaryKey::Legacy(Password::new("pw", false)));
+    app.load_diary(Diary::new());
+    app.mode = AppMode::Edit;
+    app.handle(Event::Key(KeyEvent::from(KeyCode::Char('x'))))
+        .unwrap();
+    app.write_journal();
+    assert!(app
+        .notice
+        .as_deref()
+        .unwrap()
+        .starts_with("Journaling is off"));
+}
+
+#[test]
+fn reloading_does_not_decrypt_the_diary() {
+    use crate::{events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let mut storage = MemoryStorage::new();
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    app.new_file("pw");
+    // Someone replaces the file with plaintext.
+    storage.save(&Diary::new(), &DiaryKey::Plaintext).unwrap();
+    app.mode = AppMode::ExternalChange(false);
+    app.handle(Event::Key(KeyEvent::from(KeyCode::Char('r'))))
+        .unwrap();
+    assert!(matches!(app.mode, AppMode::ExternalChange(true)));
+    assert!(!app.key.as_ref().unwrap().is_plaintext());
+}
+
+#[test]
+fn idle_lock_hides_every_screen_of_the_diary() {
+    use crate::{events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let mut storage = MemoryStorage::new();
+    storage.save(&Diary::new(), &DiaryKey::Plaintext).unwrap();
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.try_load().unwrap();
+    app.stamp = app.storage.metadata().ok();
+    app.lock_after = Some(Duration::ZERO);
+    app.mode = AppMode::Trash(0);
+    app.tick().unwrap();
+    assert!(matches!(app.mode, AppMode::Locked(_)));
+    // Unsaved changes that would overwrite a change on disk are left unsaved.
+    let mut theirs = Diary::new();
+    theirs.entries.insert(app.date, "theirs".to_owned());
+    storage.save(&theirs, &DiaryKey::Plaintext).unwrap();
+    app.saved = false;
+    app.mode = AppMode::ExternalChange(false);
+    app.tick().unwrap();
+    assert!(matches!(app.mode, AppMode::Locked(_)));
+    assert!(!app.saved);
+    let (disk, _) = storage.load(Credentials::Password("", None)).unwrap();
+    assert_eq!(disk.entries, theirs.entries);
+}
+
+#[test]
+fn merging_after_a_password_change_keeps_unsaved_changes() {
+    use crate::{events::ScriptedEvents, storage::MemoryStorage};
+    use ratatui::backend::TestBackend;
+    let mut storage = MemoryStorage::new();
+    let mut app = App::with_backend(TestBackend::new(80, 24), ScriptedEvents::default()).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    app.new_file("pw");
+    let other = Date::from(app.date.succ_opt().unwrap());
+    app.entries.insert(
+        app.date,
+        <App>::input_area(app.date, Some(&"ours".to_owned())),
+    );
+    app.saved = false;
+    // Someone saves the diary with another password meanwhile.
+    let (mut theirs, _) = storage.load(Credentials::Password("pw", None)).unwrap();
+    theirs.entries.insert(other, "theirs".to_owned());
+    let key = DiaryKey::new("new", Kdf::argon2id(64, 1, 1).unwrap(), None).unwrap();
+    storage.save(&theirs, &key).unwrap();
+    app.mode = AppMode::ExternalChange(false);
+    let press = |app: &mut App<'_, TestBackend>, code| {
+        app.handle(Event::Key(KeyEvent::from(code))).unwrap();
+    };
+    press(&mut app, KeyCode::Char('m'));
+    assert!(matches!(
+        app.mode,
+        AppMode::Reauthenticate(_, Rejoin::Merge)
+    ));
+    "new"
+        .chars()
+        .for_each(|c| press(&mut app, KeyCode::Char(c)));
+    press(&mut app, KeyCode::Enter);
+    assert!(matches!(app.mode, AppMode::Edit));
+    assert_eq!(app.entries[&app.date].lines().join("\n"), "ours");
+    assert_eq!(app.entries[&other].lines().join("\n"), "theirs");
+    app.overwrite();
+    assert!(storage.load(Credentials::Password("new", None)).is_ok());
 }
@@ -1,58 +1,333 @@
-use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::TimeDelta;
 
 use crate::{
     app::{App, AppMode},
-    diary::{Diary, DiaryFromFileError},
+    crypto::{Identity, Kdf, Keyfile, Recipient},
+    diary::DiaryFromFileError,
+    git::GitConfig,
+    secret::{Password, PasswordPolicy},
+    storage::{self, Credentials},
     ui::Date,
 };
 #[derive(Debug, clap::Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct Arguments {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Diary file, or a directory (ending in `/` if it is new) that keeps each day in a file
     #[arg(short, long)]
     file: Option<String>,
     #[arg(short, long, requires("file"))]
     password: Option<String>,
+    /// File needed along with the password to unlock the diary; new diaries will require it
+    #[arg(short, long, value_name("PATH"))]
+    keyfile: Option<String>,
+    /// Open a shared diary with this identity file instead of a password
+    #[arg(long, value_name("PATH"), requires("file"), conflicts_with("password"))]
+    identity: Option<String>,
     #[arg(short, long, value_name("DATE: DD-MM-YYYY"))]
     date: Option<Date>,
+    /// Browse the diary without being able to change it
+    #[arg(long)]
+    read_only: bool,
+    /// Days to keep deleted entries in the trash (0 keeps them forever)
+    #[arg(long, value_name("DAYS"), default_value_t = <App>::DEFAULT_TRASH_RETENTION.num_days() as u32)]
+    trash_retention: u32,
+    /// Number of earlier versions to keep for each entry
+    #[arg(long, value_name("COUNT"), default_value_t = <App>::DEFAULT_MAX_REVISIONS)]
+    max_revisions: usize,
+    /// Lock the password into RAM so it is never written to swap
+    #[arg(long)]
+    mlock: bool,
+    #[command(flatten)]
+    policy: PolicyArgs,
+    /// Lock the diary after this many seconds without input (0 disables auto-locking)
+    #[arg(short, long, value_name("SECONDS"))]
+    lock_after: Option<u64>,
+    /// Show the lock screen when journalr is resumed after being suspended with Ctrl+Z
+    #[arg(long)]
+    lock_on_resume: bool,
+    /// Commit the diary to the git repository it is in after every save
+    #[arg(long)]
+    git: bool,
+    /// Create a git repository next to the diary if there is none (implies --git)
+    #[arg(long)]
+    git_init: bool,
+    /// Commit message template; {file}, {date} and {time} are filled in
+    #[arg(long, value_name("TEMPLATE"), default_value = GitConfig::DEFAULT_MESSAGE)]
+    git_message: String,
+    #[command(flatten)]
+    kdf: KdfArgs,
+}
+// Argon2id settings for diaries created or re-keyed by this invocation. Not a doc comment, as
+// clap would make it the description of the whole program.
+#[derive(Debug, clap::Args)]
+pub struct KdfArgs {
+    /// Memory used to derive the key from the password, in MiB
+    #[arg(long, value_name("MiB"), default_value_t = Kdf::DEFAULT_MEMORY / 1024)]
+    pub kdf_memory: u32,
+    /// Passes over that memory
+    #[arg(long, value_name("COUNT"), default_value_t = Kdf::DEFAULT_ITERATIONS)]
+    pub kdf_iterations: u32,
+    /// Threads used to derive the key
+    #[arg(long, value_name("THREADS"), default_value_t = Kdf::DEFAULT_PARALLELISM)]
+    pub kdf_parallelism: u32,
+}
+// What new passwords chosen in this invocation have to satisfy.
+#[derive(Debug, clap::Args)]
+pub struct PolicyArgs {
+    /// Fewest characters a new password may have
+    #[arg(long, value_name("COUNT"), default_value_t = PasswordPolicy::default().min_length)]
+    pub min_password_length: usize,
+    /// Least estimated entropy a new password may have, in bits
+    #[arg(long, value_name("BITS"), default_value_t = PasswordPolicy::default().min_bits)]
+    pub min_password_bits: u32,
+}
+impl From<&PolicyArgs> for PasswordPolicy {
+    fn from(value: &PolicyArgs) -> Self {
+        Self {
+            min_length: value.min_password_length,
+            min_bits: value.min_password_bits,
+        }
+    }
+}
+impl TryFrom<&KdfArgs> for Kdf {
+    type Error = String;
+    fn try_from(value: &KdfArgs) -> Result<Self, Self::Error> {
+        Kdf::argon2id(
+            value.kdf_memory.saturating_mul(1024),
+            value.kdf_iterations,
+            value.kdf_parallelism,
+        )
+    }
+}
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// List git commits of a diary, or show and restore entries from one of them
+    Log {
+        file: String,
+        /// Commit to read the diary from; lists its dates unless --date is given
+        #[arg(short, long)]
+        rev: Option<String>,
+        /// Print the entry for this date from the commit given by --rev
+        #[arg(short, long, value_name("DATE: DD-MM-YYYY"), requires("rev"))]
+        date: Option<Date>,
+        /// Write the entry back into the current diary instead of printing it
+        #[arg(long, requires("date"))]
+        restore: bool,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    /// Merge two diverged copies of a diary
+    Merge {
+        a: String,
+        b: String,
+        /// Common ancestor of both copies, so one-sided deletions can be told from additions
+        #[arg(long)]
+        base: Option<String>,
+        /// Where to write the result; defaults to overwriting A
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Resolve conflicting entries side by side instead of writing conflict markers
+        #[arg(short, long)]
+        interactive: bool,
+        /// Save the result with the keys of this copy; needed when only one is encrypted
+        #[arg(long, value_enum, value_name("COPY"))]
+        key_from: Option<Side>,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    /// Change the password, keyfile or key derivation settings of a diary
+    ///
+    /// Diaries created by older versions of journalr are converted to Argon2id.
+    Rekey {
+        file: String,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+        /// Require this keyfile from now on
+        #[arg(long, value_name("PATH"))]
+        new_keyfile: Option<String>,
+        /// Stop requiring a keyfile
+        #[arg(long, conflicts_with("new_keyfile"))]
+        remove_keyfile: bool,
+        /// Generate a new recovery key, replacing any earlier one
+        #[arg(long)]
+        new_recovery_key: bool,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        policy: PolicyArgs,
+    },
+    /// Set a new password for a diary using its recovery key
+    Recover {
+        file: String,
+        /// Require this keyfile from now on
+        #[arg(short, long, value_name("PATH"))]
+        keyfile: Option<String>,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        policy: PolicyArgs,
+    },
+    /// Store a diary as plaintext JSON, or encrypt a plaintext diary
+    Convert {
+        file: String,
+        #[arg(value_enum)]
+        to: Encryption,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+        /// Require this keyfile when encrypting
+        #[arg(long, value_name("PATH"))]
+        new_keyfile: Option<String>,
+        /// Also generate a recovery key when encrypting
+        #[arg(long)]
+        new_recovery_key: bool,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        policy: PolicyArgs,
+    },
+    /// Copy a diary into a new file, directory or database, keeping its keys
+    ///
+    /// With the `sqlite` feature, paths ending in `.sqlite`, `.sqlite3` or `.db` are databases.
+    Migrate {
+        from: String,
+        to: String,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+    },
+    /// Create an identity file for opening shared diaries and print its public key
+    Keygen { output: String },
+    /// List, add or remove the people a diary is shared with
+    Recipients {
+        file: String,
+        #[command(flatten)]
+        unlock: UnlockArgs,
+        /// Lists the recipients if not given
+        #[command(subcommand)]
+        action: Option<RecipientsAction>,
+    },
+    /// Find Argon2id settings that take a given time to unlock a diary on this machine
+    KdfBench {
+        /// Unlock time to aim for, in milliseconds
+        #[arg(short, long, value_name("MS"), default_value_t = 1000)]
+        target: u64,
+        /// Least memory to use, in MiB
+        #[arg(short, long, value_name("MiB"), default_value_t = 64)]
+        memory: u32,
+        #[arg(short, long, value_name("THREADS"), default_value_t = 1)]
+        parallelism: u32,
+    },
+}
+/// Whether a diary is encrypted on disk.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Encryption {
+    /// Human-readable JSON that anyone with the file can read
+    Plaintext,
+    /// Encrypted with a password
+    Encrypted,
+}
+/// One of the two copies given to `merge`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Side {
+    A,
+    B,
+}
+/// How a subcommand unlocks the diaries it reads.
+#[derive(Debug, clap::Args)]
+pub struct UnlockArgs {
+    /// Password; asked for when a file needs one and it is not given or does not unlock it
+    #[arg(short, long)]
+    pub password: Option<String>,
+    /// Keyfile, for diaries that need one
+    #[arg(short, long, value_name("PATH"))]
+    pub keyfile: Option<String>,
+    /// Open shared diaries with this identity file instead of a password
+    #[arg(long, value_name("PATH"), conflicts_with("password"))]
+    pub identity: Option<String>,
+}
+#[derive(Debug, clap::Subcommand)]
+pub enum RecipientsAction {
+    /// Share the diary with these public keys
+    Add {
+        #[arg(required = true)]
+        recipients: Vec<Recipient>,
+    },
+    /// Stop sharing the diary with these public keys
+    Remove {
+        #[arg(required = true)]
+        recipients: Vec<Recipient>,
+    },
+    /// Stop accepting the password, so only recipients can open the diary
+    RemovePassword,
 }
 impl TryFrom<Arguments> for App<'_> {
-    type Error = DiaryFromFileError;
+    type Error = Box<dyn std::error::Error>;
     fn try_from(value: Arguments) -> Result<Self, Self::Error> {
         let mut app = App::new()?;
+        app.kdf = Kdf::try_from(&value.kdf)?;
+        app.keyfile = value.keyfile.as_deref().map(read_keyfile).transpose()?;
+        app.identity = value.identity.as_deref().map(read_identity).transpose()?;
+        app.mlock = value.mlock;
+        app.password_policy = PasswordPolicy::from(&value.policy);
+        app.read_only = value.read_only;
+        app.max_revisions = value.max_revisions;
+        app.trash_retention =
+            (value.trash_retention != 0).then(|| TimeDelta::days(value.trash_retention.into()));
         if let Some(d) = value.date {
             app.date = d
         }
+        app.lock_on_resume = value.lock_on_resume;
+        if let Some(secs) = value.lock_after {
+            app.lock_after = (secs != 0).then(|| Duration::from_secs(secs));
+        }
+        if value.git || value.git_init {
+            app.git = Some(GitConfig {
+                init: value.git_init,
+                message: value.git_message.clone(),
+            });
+        }
         match value {
+            Arguments {
+                file: Some(file),
+                identity: Some(_),
+                ..
+            } => {
+                app.storage = storage::open(&file);
+                app.try_load()?;
+                app.mode = AppMode::Edit;
+            }
             Arguments {
                 file: Some(file),
                 password: None,
                 ..
-            } => match Diary::read_jrnl(&file, "") {
-                Ok(entries) => {
-                    app.path = file;
-                    app.password = String::new();
-                    app.mode = AppMode::Edit;
-                    app.entries = HashMap::from(entries);
-                    app.entries
-                        .entry(Date::today())
-                        .or_insert(App::input_area(Date::today(), None));
-                    app.entries
-                        .iter_mut()
-                        .for_each(|(date, input)| App::setup_input_area(input, *date));
-                }
-                Err(DiaryFromFileError::WrongPassword) => {
-                    app.path = file;
-                    app.mode = AppMode::Password;
+            } => {
+                app.storage = storage::open(&file);
+                match app.storage.load(Credentials::Nothing) {
+                    Ok((diary, key)) => {
+                        app.password = Password::default();
+                        app.mode = AppMode::Edit;
+                        app.load_diary(diary);
+                        app.key = Some(key);
+                    }
+                    Err(
+                        DiaryFromFileError::WrongPassword
+                        | DiaryFromFileError::KeyfileRequired
+                        | DiaryFromFileError::WrongKeyfile,
+                    ) => app.mode = AppMode::password(),
+                    Err(e) => return Err(e.into()),
                 }
-                Err(e) => return Err(e),
-            },
+            }
             Arguments {
                 file: Some(file),
                 password: Some(password),
                 ..
             } => {
-                app.password = password;
-                app.path = file;
-                app.try_load()?;
+                app.password = Password::new(password, app.mlock);
+                app.storage = storage::open(&file);
+                app.load_with_password()?;
                 app.mode = AppMode::Edit;
             }
             _ => (),
@@ -60,3 +335,10 @@ impl TryFrom<Arguments> for App<'_> {
         Ok(app)
     }
 }
+
+pub(crate) fn read_keyfile(path: &str) -> Result<Keyfile, String> {
+    Keyfile::read(path).map_err(|e| format!("Cannot read keyfile {path}: {e}"))
+}
+pub(crate) fn read_identity(path: &str) -> Result<Identity, String> {
+    Identity::read(path).map_err(|e| format!("Cannot read identity {path}: {e}"))
+}
@@ -0,0 +1,202 @@
+//! Record of password attempts on each diary, to slow down and expose guessing.
+//!
+//! The record is a JSON file in the user's state directory, keyed by the canonical path of each
+//! diary, so it survives restarting journalr between guesses.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::diary::DiaryFromFileError;
+
+/// Unlock history of one diary.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnlockRecord {
+    /// Wrong passwords since the last successful unlock.
+    pub failures: u32,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+}
+impl UnlockRecord {
+    /// How long to refuse password attempts after the last failure.
+    fn backoff(&self) -> Duration {
+        match self.failures {
+            0..=2 => Duration::ZERO,
+            n => Duration::from_secs(2u64.saturating_pow(n - 2).min(300)),
+        }
+    }
+    /// What to tell the user after they unlocked the diary, if anything.
+    pub fn summary(&self) -> Option<String> {
+        let time = |t: &DateTime<Utc>| t.with_timezone(&Local).format("%d-%m-%Y %H:%M");
+        let success = self
+            .last_success
+            .as_ref()
+            .map(|t| format!("Last unlocked {}", time(t)));
+        let failures = self
+            .last_failure
+            .as_ref()
+            .filter(|_| self.failures > 0)
+            .map(|t| {
+                format!(
+                    "{} wrong password(s) since then, the last at {}",
+                    self.failures,
+                    time(t)
+                )
+            });
+        match (success, failures) {
+            (None, None) => None,
+            (s, f) => Some([s, f].into_iter().flatten().collect::<Vec<_>>().join("\n")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AttemptError {
+    /// Too many recent wrong passwords; attempts are refused for this much longer.
+    Refused(Duration),
+    Failed(DiaryFromFileError),
+}
+impl Display for AttemptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Refused(wait) => write!(
+                f,
+                "Too many wrong passwords; try again in {} s",
+                wait.as_secs() + 1
+            ),
+            Self::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for AttemptError {}
+
+#[derive(Clone)]
+pub struct AttemptLog {
+    path: PathBuf,
+}
+impl AttemptLog {
+    /// The log in the user's state directory.
+    pub fn new() -> Self {
+        Self::at(state_dir().join("journalr").join("attempts.json"))
+    }
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+    fn load(&self) -> HashMap<String, UnlockRecord> {
+        fs::read(&self.path)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+    /// Apply `f` to the record of `diary`, re-reading the file first so concurrent journalr
+    /// processes don't undo each other's updates.
+    fn update<T>(&self, diary: &str, f: impl FnOnce(&mut UnlockRecord) -> T) -> io::Result<T> {
+        let mut records = self.load();
+        let res = f(records.entry(key(diary)).or_default());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(&records)?)?;
+        Ok(res)
+    }
+    pub fn record(&self, diary: &str) -> UnlockRecord {
+        self.load().remove(&key(diary)).unwrap_or_default()
+    }
+    /// How much longer password attempts on `diary` should be refused.
+    pub fn wait(&self, diary: &str) -> Duration {
+        let record = self.record(diary);
+        let since = record
+            .last_failure
+            .and_then(|t| (Utc::now() - t).to_std().ok())
+            .unwrap_or_default();
+        record.backoff().saturating_sub(since)
+    }
+    pub fn failed(&self, diary: &str) -> io::Result<()> {
+        self.update(diary, |record| {
+            record.failures += 1;
+            record.last_failure = Some(Utc::now());
+        })
+    }
+    /// Check a password for `diary` with `check`, unless attempts are being refused. Wrong
+    /// passwords and keyfiles count as failures and anything else that unlocks as a success.
+    pub fn attempt<T>(
+        &self,
+        diary: &str,
+        check: impl FnOnce() -> Result<T, DiaryFromFileError>,
+    ) -> Result<T, AttemptError> {
+        let wait = self.wait(diary);
+        if !wait.is_zero() {
+            return Err(AttemptError::Refused(wait));
+        }
+        let res = check();
+        match &res {
+            Ok(_) => drop(self.succeeded(diary)),
+            Err(DiaryFromFileError::WrongPassword | DiaryFromFileError::WrongKeyfile) => {
+                drop(self.failed(diary))
+            }
+            Err(_) => (),
+        }
+        res.map_err(AttemptError::Failed)
+    }
+    /// Reset the failure count of `diary`, returning its record from before.
+    pub fn succeeded(&self, diary: &str) -> io::Result<UnlockRecord> {
+        self.update(diary, |record| {
+            let previous = record.clone();
+            record.failures = 0;
+            record.last_success = Some(Utc::now());
+            previous
+        })
+    }
+}
+impl Default for AttemptLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn key(diary: &str) -> String {
+    Path::new(diary)
+        .canonicalize()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| diary.to_owned())
+}
+fn state_dir() -> PathBuf {
+    let var = |name| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    var("XDG_STATE_HOME")
+        .or_else(|| var("LOCALAPPDATA"))
+        .or_else(|| var("HOME").map(|home| home.join(".local").join("state")))
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+#[test]
+fn failures_back_off_and_reset_on_success() {
+    let path = std::env::temp_dir().join(format!("journalr-attempts-{}.json", std::process::id()));
+    let log = AttemptLog::at(&path);
+    for _ in 0..2 {
+        log.failed("diary.jrnl").unwrap();
+    }
+    assert_eq!(log.wait("diary.jrnl"), Duration::ZERO);
+    log.failed("diary.jrnl").unwrap();
+    assert!(log.wait("diary.jrnl") > Duration::ZERO);
+    assert!(matches!(
+        log.attempt("diary.jrnl", || -> Result<(), _> { unreachable!() }),
+        Err(AttemptError::Refused(_))
+    ));
+    assert_eq!(log.wait("other.jrnl"), Duration::ZERO);
+    let previous = log.succeeded("diary.jrnl").unwrap();
+    assert_eq!(previous.failures, 3);
+    assert!(previous.summary().is_some());
+    assert_eq!(log.record("diary.jrnl").failures, 0);
+    assert_eq!(log.wait("diary.jrnl"), Duration::ZERO);
+    fs::remove_file(&path).unwrap();
+}
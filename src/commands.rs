@@ -0,0 +1,623 @@
+//! Subcommands that run without the editor.
+use std::{
+    cell::RefCell,
+    error::Error,
+    io::{self, stderr, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crossterm::{
+    event::{read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    ExecutableCommand,
+};
+use ratatui::{backend::CrosstermBackend, Terminal};
+
+use crate::{
+    app::App,
+    args::{read_identity, read_keyfile, Command, Encryption, RecipientsAction, Side, UnlockArgs},
+    attempts::{AttemptError, AttemptLog},
+    crypto::{DiaryKey, Identity, Kdf, Keyfile, RecoveryKey},
+    date::Date,
+    diary::{Diary, DiaryFromFileError, Merge},
+    git::Repo,
+    lock::DiaryLock,
+    secret::{Password, PasswordPolicy, Zeroize},
+    storage::{self, Credentials, Storage},
+    ui::merge::conflict_ui,
+};
+
+impl Command {
+    pub fn run(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Command::Log {
+                file,
+                rev,
+                date,
+                restore,
+                unlock,
+            } => log(&file, rev.as_deref(), date, restore, unlock),
+            Command::Merge {
+                a,
+                b,
+                base,
+                output,
+                interactive,
+                key_from,
+                unlock,
+            } => merge(
+                &a,
+                &b,
+                base.as_deref(),
+                output.as_deref(),
+                interactive,
+                key_from,
+                Unlock::new(unlock)?,
+            ),
+            Command::Rekey {
+                file,
+                unlock,
+                new_keyfile,
+                remove_keyfile,
+                new_recovery_key,
+                kdf,
+                policy,
+            } => {
+                let new_keyfile = new_keyfile.as_deref().map(read_keyfile).transpose()?;
+                rekey(
+                    &file,
+                    Unlock::new(unlock)?,
+                    new_keyfile.map(Some).or(remove_keyfile.then_some(None)),
+                    new_recovery_key,
+                    Kdf::try_from(&kdf)?,
+                    PasswordPolicy::from(&policy),
+                )
+            }
+            Command::Recover {
+                file,
+                keyfile,
+                kdf,
+                policy,
+            } => recover(
+                &file,
+                keyfile.as_deref().map(read_keyfile).transpose()?,
+                Kdf::try_from(&kdf)?,
+                PasswordPolicy::from(&policy),
+            ),
+            Command::Convert {
+                file,
+                to,
+                unlock,
+                new_keyfile,
+                new_recovery_key,
+                kdf,
+                policy,
+            } => convert(
+                &file,
+                to,
+                Unlock::new(unlock)?,
+                new_keyfile.as_deref().map(read_keyfile).transpose()?,
+                new_recovery_key,
+                Kdf::try_from(&kdf)?,
+                PasswordPolicy::from(&policy),
+            ),
+            Command::Migrate { from, to, unlock } => migrate(&from, &to, Unlock::new(unlock)?),
+            Command::Keygen { output } => keygen(&output),
+            Command::Recipients {
+                file,
+                unlock,
+                action,
+            } => recipients(&file, Unlock::new(unlock)?, action),
+            Command::KdfBench {
+                target,
+                memory,
+                parallelism,
+            } => kdf_bench(Duration::from_millis(target), memory, parallelism),
+        }
+    }
+}
+
+/// Read a password from the terminal without echoing it.
+pub fn prompt_password(prompt: &str) -> io::Result<Password> {
+    let mut err = stderr();
+    write!(err, "{prompt}")?;
+    err.flush()?;
+    enable_raw_mode()?;
+    let mut input = String::new();
+    let res = loop {
+        match read() {
+            Ok(Event::Key(k)) if k.kind != KeyEventKind::Release => match k {
+                KeyEvent {
+                    code: KeyCode::Enter,
+                    ..
+                } => break Ok(()),
+                KeyEvent {
+                    code: KeyCode::Char('c'),
+                    modifiers: KeyModifiers::CONTROL,
+                    ..
+                }
+                | KeyEvent {
+                    code: KeyCode::Esc, ..
+                } => break Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled")),
+                KeyEvent {
+                    code: KeyCode::Backspace,
+                    ..
+                } => {
+                    input.pop();
+                }
+                KeyEvent {
+                    code: KeyCode::Char(c),
+                    ..
+                } => input.push(c),
+                _ => (),
+            },
+            Ok(_) => (),
+            Err(e) => break Err(e),
+        }
+    };
+    disable_raw_mode()?;
+    writeln!(err)?;
+    let password = Password::new(input.as_str(), false);
+    input.zeroize();
+    res.map(|_| password)
+}
+/// Read [`UnlockArgs`] files and ask for the password if it is needed and not given.
+struct Unlock {
+    /// The password given or last typed, kept for the next diary.
+    password: RefCell<Option<Password>>,
+    keyfile: Option<Keyfile>,
+    identity: Option<Identity>,
+    /// Passwords are checked with the same backoff as in the editor.
+    attempts: AttemptLog,
+}
+impl Unlock {
+    fn new(args: UnlockArgs) -> Result<Self, Box<dyn Error>> {
+        let identity = args.identity.as_deref().map(read_identity).transpose()?;
+        Ok(Self {
+            password: RefCell::new(args.password.map(|p| Password::new(p, false))),
+            keyfile: args.keyfile.as_deref().map(read_keyfile).transpose()?,
+            identity,
+            attempts: AttemptLog::new(),
+        })
+    }
+    /// Open the contents of a diary file called `name`.
+    fn open_bytes(&self, bytes: &[u8], name: &str) -> Result<(Diary, DiaryKey), Box<dyn Error>> {
+        self.unlock(name, |credentials| {
+            storage::open_diary(&mut &*bytes, credentials)
+        })
+    }
+    fn load(&self, storage: &mut dyn Storage) -> Result<(Diary, DiaryKey), Box<dyn Error>> {
+        let name = storage.name().to_owned();
+        self.unlock(&name, |credentials| storage.load(credentials))
+    }
+    /// Unlock the diary called `name` with `open`, asking for its password if the one known so
+    /// far does not unlock it. Plaintext diaries open without asking.
+    fn unlock(
+        &self,
+        name: &str,
+        mut open: impl FnMut(Credentials<'_>) -> Result<(Diary, DiaryKey), DiaryFromFileError>,
+    ) -> Result<(Diary, DiaryKey), Box<dyn Error>> {
+        let keyfile = self.keyfile.as_ref();
+        let res = match &self.identity {
+            Some(identity) => open(Credentials::Identity(identity)).map_err(AttemptError::Failed),
+            None => {
+                let known = self.password.borrow();
+                let res = match known.as_deref() {
+                    Some(password) => self
+                        .attempts
+                        .attempt(name, || open(Credentials::Password(password, keyfile))),
+                    // Only finding out whether the diary needs a password, which is no guess.
+                    None => open(Credentials::Nothing).map_err(AttemptError::Failed),
+                };
+                drop(known);
+                match res {
+                    Err(AttemptError::Failed(DiaryFromFileError::WrongPassword)) => {
+                        let password = prompt_password(&format!("Password for {name}: "))?;
+                        let res = self
+                            .attempts
+                            .attempt(name, || open(Credentials::Password(&password, keyfile)));
+                        if res.is_ok() {
+                            *self.password.borrow_mut() = Some(password);
+                        }
+                        res
+                    }
+                    res => res,
+                }
+            }
+        };
+        res.map_err(|e| format!("{name}: {e}").into())
+    }
+}
+/// Ask `question` and wait for a line; true if it starts with `y`.
+fn confirm(question: &str) -> io::Result<bool> {
+    let mut err = stderr();
+    write!(err, "{question} (y/n) ")?;
+    err.flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim_start().starts_with(['y', 'Y']))
+}
+/// Keep the editor and other subcommands from writing the diary until the lock is dropped.
+fn lock(storage: &dyn Storage) -> Result<Option<DiaryLock>, Box<dyn Error>> {
+    let name = storage.name();
+    storage.lock().map_err(|e| format!("{name}: {e}").into())
+}
+fn save(storage: &mut dyn Storage, diary: &Diary, key: &DiaryKey) -> Result<(), Box<dyn Error>> {
+    let name = storage.name().to_owned();
+    storage
+        .save(diary, key)
+        .map_err(|e| format!("{name}: {e}").into())
+}
+fn prompt_new_password(policy: PasswordPolicy) -> Result<Password, Box<dyn Error>> {
+    let new = prompt_password("New password: ")?;
+    policy.check(&new)?;
+    if *prompt_password("Retype new password: ")? != *new {
+        return Err("Passwords don't match".into());
+    }
+    Ok(new)
+}
+
+fn log(
+    file: &str,
+    rev: Option<&str>,
+    date: Option<Date>,
+    restore: bool,
+    unlock: UnlockArgs,
+) -> Result<(), Box<dyn Error>> {
+    let repo = Repo::discover(file)?;
+    let Some(rev) = rev else {
+        for commit in repo.log(file)? {
+            println!(
+                "{}  {}  {}",
+                &commit.hash[..commit.hash.len().min(10)],
+                commit.date,
+                commit.subject
+            );
+        }
+        return Ok(());
+    };
+    let unlock = Unlock::new(unlock)?;
+    let (old, _) = unlock.open_bytes(&repo.show(rev, file)?, rev)?;
+    let Some(date) = date else {
+        let mut dates = old.entries.keys().collect::<Vec<_>>();
+        dates.sort();
+        dates.iter().for_each(|d| println!("{d}"));
+        return Ok(());
+    };
+    let Some(text) = old.entries.get(&date) else {
+        return Err(format!("No entry for {date} in {rev}").into());
+    };
+    if restore {
+        // Same as a save in the editor: not while it is open there, and committed afterwards.
+        let mut storage = storage::open(file);
+        let _lock = lock(&*storage)?;
+        let (mut current, key) = unlock.load(&mut *storage)?;
+        let previous = current.entries.clone();
+        current.entries.insert(date, text.clone());
+        current.record_revisions(&previous, <App>::DEFAULT_MAX_REVISIONS);
+        save(&mut *storage, &current, &key)?;
+        println!("Restored the entry for {date} from {rev}");
+        repo.commit_file(file, &format!("journalr: restore {date} from {rev}"))?;
+    } else {
+        println!("{text}");
+    }
+    Ok(())
+}
+
+fn merge(
+    a: &str,
+    b: &str,
+    base: Option<&str>,
+    output: Option<&str>,
+    interactive: bool,
+    key_from: Option<Side>,
+    unlock: Unlock,
+) -> Result<(), Box<dyn Error>> {
+    let output = output.unwrap_or(a);
+    let mut storages = vec![storage::open(a), storage::open(b)];
+    // Saved through the storage it was loaded from, which knows what is there already.
+    let target = match output {
+        output if output == a => 0,
+        output if output == b => 1,
+        output => {
+            storages.push(storage::open(output));
+            2
+        }
+    };
+    let _lock = lock(&*storages[target])?;
+    let (ours, our_key) = unlock.load(&mut *storages[0])?;
+    let (theirs, their_key) = unlock.load(&mut *storages[1])?;
+    let plaintext = (our_key.is_plaintext(), their_key.is_plaintext());
+    let key = match key_from {
+        Some(Side::A) => our_key,
+        Some(Side::B) => their_key,
+        None if plaintext.0 == plaintext.1 => our_key,
+        None => {
+            let (plain, encrypted) = if plaintext.0 { (a, b) } else { (b, a) };
+            return Err(format!(
+                "{plain} is plaintext but {encrypted} is encrypted; \
+                 choose whose keys the result gets with --key-from"
+            )
+            .into());
+        }
+    };
+    if key.is_plaintext() && !(plaintext.0 && plaintext.1) {
+        let encrypted = if plaintext.0 { b } else { a };
+        if !confirm(&format!(
+            "{output} will not be encrypted, although {encrypted} is. Continue?"
+        ))? {
+            return Err("Nothing was merged".into());
+        }
+    }
+    let base = base
+        .map(|base| unlock.load(&mut *storage::open(base)))
+        .transpose()?;
+    let mut merge = Diary::merge(base.as_ref().map(|(d, _)| d), &ours, &theirs, (a, b));
+    if interactive && !merge.conflicts.is_empty() {
+        resolve_conflicts(&mut merge, (a, b))?;
+    }
+    save(&mut *storages[target], &merge.diary, &key)?;
+    println!(
+        "Merged into {output} with {} conflicting entries",
+        merge.conflicts.len()
+    );
+    Ok(())
+}
+/// Let the user pick a side for each conflict. Resolved conflicts are removed from `merge`.
+fn resolve_conflicts(merge: &mut Merge, names: (&str, &str)) -> io::Result<()> {
+    let conflicts = std::mem::take(&mut merge.conflicts);
+    let total = conflicts.len();
+    enable_raw_mode()?;
+    stderr().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stderr()))?;
+    let mut index = 0;
+    let res = loop {
+        let Some(conflict) = conflicts.get(index) else {
+            break Ok(());
+        };
+        if let Err(e) =
+            terminal.draw(|f| conflict_ui(f.buffer_mut(), conflict, (index, total), names))
+        {
+            break Err(e);
+        }
+        let chosen = match read() {
+            Ok(Event::Key(k)) if k.kind != KeyEventKind::Release => match k.code {
+                KeyCode::Left => Some(&conflict.ours),
+                KeyCode::Right => Some(&conflict.theirs),
+                KeyCode::Char('b') => None,
+                KeyCode::Esc => break Ok(()),
+                _ => continue,
+            },
+            Ok(_) => continue,
+            Err(e) => break Err(e),
+        };
+        match chosen {
+            Some(Some(text)) => {
+                merge.diary.entries.insert(conflict.date, text.clone());
+            }
+            Some(None) => {
+                merge.diary.entries.remove(&conflict.date);
+            }
+            None => merge.conflicts.push(conflict.clone()),
+        }
+        index += 1;
+    };
+    merge.conflicts.extend(conflicts.into_iter().skip(index));
+    disable_raw_mode()?;
+    stderr().execute(LeaveAlternateScreen)?;
+    res
+}
+
+/// Protect `file` with a new password. `new_keyfile` replaces or, if `Some(None)`, removes
+/// the keyfile; by default the diary keeps needing the one it needs now.
+fn rekey(
+    file: &str,
+    unlock: Unlock,
+    new_keyfile: Option<Option<Keyfile>>,
+    new_recovery_key: bool,
+    kdf: Kdf,
+    policy: PasswordPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let mut storage = storage::open(file);
+    let _lock = lock(&*storage)?;
+    let (diary, key) = unlock.load(&mut *storage)?;
+    let keyfile = match new_keyfile {
+        Some(new) => new,
+        None if key.needs_keyfile() => unlock.keyfile,
+        None => None,
+    };
+    let new = prompt_new_password(policy)?;
+    let mut key = key.rekey(&new, kdf, keyfile.as_ref())?;
+    let recovery = new_recovery_key.then(|| key.add_recovery()).transpose()?;
+    save(&mut *storage, &diary, &key)?;
+    let with_keyfile = if keyfile.is_some() {
+        " and a keyfile"
+    } else {
+        ""
+    };
+    println!("{file} is now protected with {kdf}{with_keyfile}");
+    if let Some(recovery) = recovery {
+        println!("Recovery key: {}", *recovery.encode());
+        println!("Write it down and keep it somewhere safe; it will not be shown again.");
+    }
+    Ok(())
+}
+fn recover(
+    file: &str,
+    keyfile: Option<Keyfile>,
+    kdf: Kdf,
+    policy: PasswordPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let mut storage = storage::open(file);
+    let _lock = lock(&*storage)?;
+    let typed = prompt_password("Recovery key: ")?;
+    let recovery = RecoveryKey::parse(&typed).ok_or("That is not a recovery key")?;
+    let (diary, key) = match storage.load(Credentials::Recovery(&recovery)) {
+        Err(DiaryFromFileError::WrongPassword) => return Err("Wrong recovery key".into()),
+        res => res.map_err(|e| format!("{file}: {e}"))?,
+    };
+    let new = prompt_new_password(policy)?;
+    let key = key.rekey(&new, kdf, keyfile.as_ref())?;
+    save(&mut *storage, &diary, &key)?;
+    println!("Set a new password for {file}; the recovery key still works");
+    Ok(())
+}
+fn convert(
+    file: &str,
+    to: Encryption,
+    unlock: Unlock,
+    keyfile: Option<Keyfile>,
+    new_recovery_key: bool,
+    kdf: Kdf,
+    policy: PasswordPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let mut storage = storage::open(file);
+    let _lock = lock(&*storage)?;
+    let (diary, key) = unlock.load(&mut *storage)?;
+    match to {
+        Encryption::Plaintext if key.is_plaintext() => {
+            return Err(format!("{file} is already plaintext").into())
+        }
+        Encryption::Plaintext => {
+            save(&mut *storage, &diary, &DiaryKey::Plaintext)?;
+            println!("{file} is no longer encrypted; anyone who can read the file can read it");
+        }
+        Encryption::Encrypted if !key.is_plaintext() => {
+            return Err(format!(
+                "{file} is already encrypted; use `journalr rekey` to change its keys"
+            )
+            .into())
+        }
+        Encryption::Encrypted => {
+            let new = prompt_new_password(policy)?;
+            let mut key = DiaryKey::new(&new, kdf, keyfile.as_ref())?;
+            let recovery = new_recovery_key.then(|| key.add_recovery()).transpose()?;
+            save(&mut *storage, &diary, &key)?;
+            println!("{file} is now encrypted with {kdf}");
+            if let Some(recovery) = recovery {
+                println!("Recovery key: {}", *recovery.encode());
+                println!("Write it down and keep it somewhere safe; it will not be shown again.");
+            }
+        }
+    }
+    Ok(())
+}
+fn migrate(from: &str, to: &str, unlock: Unlock) -> Result<(), Box<dyn Error>> {
+    if Path::new(to).exists() {
+        return Err(format!("{to} already exists").into());
+    }
+    let (diary, key) = unlock.load(&mut *storage::open(from))?;
+    let mut target = storage::open(to);
+    target.create()?;
+    target
+        .save(&diary, &key)
+        .map_err(|e| format!("{to}: {e}"))?;
+    println!("Copied {from} to {to}; check that it opens before removing {from}");
+    Ok(())
+}
+fn keygen(output: &str) -> Result<(), Box<dyn Error>> {
+    let identity = Identity::generate();
+    identity
+        .write(output)
+        .map_err(|e| format!("Cannot write {output}: {e}"))?;
+    println!("Public key: {}", identity.recipient());
+    println!("Give it to others so they can share diaries with you; keep {output} secret.");
+    Ok(())
+}
+fn recipients(
+    file: &str,
+    unlock: Unlock,
+    action: Option<RecipientsAction>,
+) -> Result<(), Box<dyn Error>> {
+    let mut storage = storage::open(file);
+    // Only listing them leaves the diary as it is.
+    let _lock = action.is_some().then(|| lock(&*storage)).transpose()?;
+    let (diary, mut key) = unlock.load(&mut *storage)?;
+    // Whoever loses access may have kept the content key, so it is replaced.
+    let rotates = matches!(
+        action,
+        Some(RecipientsAction::Remove { .. } | RecipientsAction::RemovePassword)
+    );
+    match action {
+        None => {
+            match key.kdf() {
+                Some(kdf) => println!("password ({kdf})"),
+                None if key.has_password() => println!("password"),
+                None => (),
+            }
+            key.recipients().iter().for_each(|r| println!("{r}"));
+            return Ok(());
+        }
+        Some(RecipientsAction::Add { recipients }) => {
+            for recipient in recipients {
+                if !key.add_recipient(recipient)? {
+                    println!("{recipient} can already open {file}");
+                }
+            }
+        }
+        Some(RecipientsAction::Remove { recipients }) => {
+            for recipient in recipients {
+                if !key.remove_recipient(recipient)? {
+                    println!("{recipient} could not open {file}");
+                }
+            }
+        }
+        Some(RecipientsAction::RemovePassword) => key.remove_password()?,
+    }
+    let recovery = match rotates {
+        true => rotate(&mut key, file, &unlock)?,
+        false => None,
+    };
+    save(&mut *storage, &diary, &key)?;
+    if rotates {
+        println!(
+            "{file} has a new key; what is saved from now on cannot be opened with what was removed"
+        );
+        println!(
+            "Copies saved before now, including backups and git history, still open as before"
+        );
+    }
+    if let Some(recovery) = recovery {
+        println!("New recovery key: {}", *recovery.encode());
+        println!("The old recovery key no longer works on new saves.");
+        println!("Write it down and keep it somewhere safe; it will not be shown again.");
+    }
+    Ok(())
+}
+/// Give `key` a new content key after someone lost access to the diary, using the password it
+/// was unlocked with or asking for it.
+fn rotate(
+    key: &mut DiaryKey,
+    file: &str,
+    unlock: &Unlock,
+) -> Result<Option<RecoveryKey>, Box<dyn Error>> {
+    let keyfile = unlock.keyfile.as_ref();
+    let known = unlock.password.borrow();
+    let res = key.rotate(known.as_deref().unwrap_or(""), keyfile);
+    drop(known);
+    Ok(match res {
+        // Unlocked with an identity, so the password is not known yet.
+        Err(DiaryFromFileError::WrongPassword) => {
+            let password = prompt_password(&format!("Password for {file}: "))?;
+            unlock
+                .attempts
+                .attempt(file, || key.rotate(&password, keyfile))?
+        }
+        res => res?,
+    })
+}
+fn kdf_bench(target: Duration, memory: u32, parallelism: u32) -> Result<(), Box<dyn Error>> {
+    let kdf = Kdf::calibrate(target, memory.saturating_mul(1024), parallelism)?;
+    let Kdf::Argon2id {
+        memory,
+        iterations,
+        parallelism,
+    } = kdf;
+    println!("{kdf}: unlocks in about {} ms", kdf.time().as_millis());
+    println!(
+        "Use with: --kdf-memory {} --kdf-iterations {iterations} --kdf-parallelism {parallelism}",
+        memory / 1024
+    );
+    Ok(())
+}
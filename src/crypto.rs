@@ -0,0 +1,1012 @@
+//! On-disk encryption of diaries.
+//!
+//! Three formats are read:
+//! - Plaintext files are the diary's JSON as is, for diaries the user chose not to encrypt.
+//! - Legacy files are a bare cocoon container keyed by the password through cocoon's own PBKDF2.
+//! - Current files start with [`MAGIC`], followed by a length-prefixed JSON [`Header`] and a
+//!   cocoon container encrypted with a random content key. The header holds that content key
+//!   wrapped under a key derived from the password, along with the KDF and its parameters.
+//!
+//! Because the content key is wrapped rather than derived, changing the password or the KDF
+//! only rewrites the header, and saving an open diary does not run the KDF again.
+//!
+//! A diary can also require a [`Keyfile`], whose hash is passed to Argon2 as its secret input
+//! so neither the password nor the keyfile alone unlocks it.
+//!
+//! The header can hold a second copy of the content key wrapped under a [`RecoveryKey`], which
+//! unlocks the diary on its own when the password is forgotten.
+//!
+//! Shared diaries also wrap the content key for any number of X25519 [`Recipient`]s, each of
+//! whom unlocks it with their own [`Identity`] file, like age does. Such a diary may have no
+//! password at all.
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use cocoon::{Cocoon, MiniCocoon};
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    diary::DiaryFromFileError,
+    secret::{Password, Zeroizing},
+};
+
+/// Start of every file in the current format. Legacy cocoon files start with `0x7f 0xc0`.
+pub const MAGIC: &[u8; 8] = b"JRNL\x00\x00\x00\x02";
+const KEY_SIZE: usize = 32;
+type KeyBytes = Zeroizing<[u8; KEY_SIZE]>;
+
+/// How a password is turned into a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum Kdf {
+    Argon2id {
+        /// Memory cost in KiB.
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::Argon2id {
+            memory: Self::DEFAULT_MEMORY,
+            iterations: Self::DEFAULT_ITERATIONS,
+            parallelism: Self::DEFAULT_PARALLELISM,
+        }
+    }
+}
+impl Kdf {
+    /// Settings of new diaries unless chosen otherwise; the memory is in KiB.
+    pub const DEFAULT_MEMORY: u32 = 64 * 1024;
+    pub const DEFAULT_ITERATIONS: u32 = 3;
+    pub const DEFAULT_PARALLELISM: u32 = 1;
+    /// Most memory, in KiB, a diary may ask for to be unlocked; as much as `calibrate` uses.
+    pub const MAX_MEMORY: u32 = 1024 * 1024;
+    pub const MAX_ITERATIONS: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 16;
+    pub fn argon2id(memory: u32, iterations: u32, parallelism: u32) -> Result<Self, String> {
+        let kdf = Self::Argon2id {
+            memory,
+            iterations,
+            parallelism,
+        };
+        kdf.check()?;
+        Ok(kdf)
+    }
+    /// Whether the settings are valid and within the limits above, which keep a crafted header
+    /// from making unlocking take unbounded memory or time.
+    fn check(&self) -> Result<(), String> {
+        match *self {
+            Self::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                if memory > Self::MAX_MEMORY {
+                    return Err(format!("at most {} MiB of memory", Self::MAX_MEMORY / 1024));
+                }
+                if iterations > Self::MAX_ITERATIONS {
+                    return Err(format!("at most {} iterations", Self::MAX_ITERATIONS));
+                }
+                if parallelism > Self::MAX_PARALLELISM {
+                    return Err(format!("at most {} threads", Self::MAX_PARALLELISM));
+                }
+                Params::new(memory, iterations, parallelism, Some(KEY_SIZE))
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+    fn derive(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        keyfile: Option<&Keyfile>,
+    ) -> Result<KeyBytes, DiaryFromFileError> {
+        // The settings come from the file header, which anyone may have written.
+        self.check()
+            .map_err(|_| DiaryFromFileError::InvalidFormat)?;
+        let mut key = Zeroizing::new([0; KEY_SIZE]);
+        match *self {
+            Self::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory, iterations, parallelism, Some(KEY_SIZE))
+                    .map_err(|_| DiaryFromFileError::InvalidFormat)?;
+                let argon2 = match keyfile {
+                    Some(keyfile) => Argon2::new_with_secret(
+                        &*keyfile.0,
+                        Algorithm::Argon2id,
+                        Version::V0x13,
+                        params,
+                    )
+                    .map_err(|_| DiaryFromFileError::InvalidFormat)?,
+                    None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+                };
+                argon2
+                    .hash_password_into(password, salt, &mut *key)
+                    .map_err(|_| DiaryFromFileError::InvalidFormat)?;
+            }
+        }
+        Ok(key)
+    }
+    /// How long deriving a key takes on this machine.
+    pub fn time(&self) -> Duration {
+        let start = Instant::now();
+        let _ = self.derive(b"journalr benchmark", &[0; 16], None);
+        start.elapsed()
+    }
+    /// Argon2id parameters for which unlocking takes about `target` on this machine, using
+    /// at least `memory` KiB.
+    pub fn calibrate(target: Duration, memory: u32, parallelism: u32) -> Result<Self, String> {
+        let mut memory = memory;
+        loop {
+            let one = Self::argon2id(memory, 1, parallelism)?
+                .time()
+                .max(Duration::from_millis(1));
+            let iterations = (target.as_secs_f64() / one.as_secs_f64()).round().max(1.0) as u32;
+            // Memory is what makes Argon2 expensive to attack, so prefer it over iterations
+            // once there is time to spare.
+            if iterations <= 4 || memory >= 1024 * 1024 {
+                return Self::argon2id(memory, iterations, parallelism);
+            }
+            memory *= 2;
+        }
+    }
+}
+impl std::fmt::Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => write!(
+                f,
+                "Argon2id, {} MiB, {iterations} iterations, parallelism {parallelism}",
+                memory / 1024
+            ),
+        }
+    }
+}
+
+/// The unencrypted first part of a current format file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    /// Absent for diaries only recipients can open.
+    #[serde(flatten)]
+    password: Option<PasswordSlot>,
+    /// The content key under the recovery key, hex encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovery: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<RecipientSlot>,
+}
+/// The content key wrapped under a key derived from the password.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PasswordSlot {
+    kdf: Kdf,
+    /// KDF salt, hex encoded.
+    salt: String,
+    /// The content key as a cocoon container under the password key, hex encoded.
+    wrapped_key: String,
+    /// Hex encoded check value of the keyfile, for diaries that require one.
+    ///
+    /// Keyfiles are expected to hold far more entropy than a password, so this lets a wrong
+    /// keyfile be reported as such without making the password any easier to guess.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyfile: Option<String>,
+}
+/// The content key wrapped for one recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecipientSlot {
+    recipient: Recipient,
+    /// Public half of the ephemeral key the wrapping key was agreed with, hex encoded.
+    ephemeral: String,
+    wrapped_key: String,
+}
+
+/// The SHA-256 hash of a keyfile, which can be any file.
+pub struct Keyfile(KeyBytes);
+impl Keyfile {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(Self(Zeroizing::new(hasher.finalize().into())))
+    }
+    fn check(&self, salt: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"journalr keyfile check");
+        hasher.update(salt);
+        hasher.update(*self.0);
+        HEXLOWER.encode(&hasher.finalize()[..16])
+    }
+}
+
+/// A random key that can unlock a diary in place of its password and keyfile.
+///
+/// It is shown to the user once, as base32 in groups of four characters.
+pub struct RecoveryKey(Zeroizing<Vec<u8>>);
+impl RecoveryKey {
+    const LEN: usize = 20;
+    fn generate() -> Self {
+        let mut bytes = Zeroizing::new(vec![0; Self::LEN]);
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+    /// Read a recovery key as typed by the user, ignoring case, spaces and dashes.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = Zeroizing::new(
+            text.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_uppercase())
+                .collect::<String>(),
+        );
+        let bytes = Zeroizing::new(BASE32_NOPAD.decode(text.as_bytes()).ok()?);
+        (bytes.len() == Self::LEN).then_some(Self(bytes))
+    }
+    pub fn encode(&self) -> Zeroizing<String> {
+        let text = Zeroizing::new(BASE32_NOPAD.encode(&self.0));
+        Zeroizing::new(
+            text.as_bytes()
+                .chunks(4)
+                .map(|group| String::from_utf8_lossy(group))
+                .collect::<Vec<_>>()
+                .join("-"),
+        )
+    }
+    fn wrapping_key(&self) -> KeyBytes {
+        let mut hasher = Sha256::new();
+        hasher.update(b"journalr recovery key");
+        hasher.update(&*self.0);
+        Zeroizing::new(hasher.finalize().into())
+    }
+}
+
+/// The public key of someone a diary is shared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Recipient(PublicKey);
+impl Recipient {
+    const PREFIX: &'static str = "journalr-pk-";
+}
+impl Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = BASE32_NOPAD.encode(self.0.as_bytes()).to_lowercase();
+        write!(f, "{}{key}", Self::PREFIX)
+    }
+}
+impl FromStr for Recipient {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s
+            .trim()
+            .strip_prefix(Self::PREFIX)
+            .and_then(|key| BASE32_NOPAD.decode(key.to_uppercase().as_bytes()).ok())
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| format!("\"{s}\" is not a journalr public key"))?;
+        Ok(Self(key.into()))
+    }
+}
+impl TryFrom<String> for Recipient {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl From<Recipient> for String {
+    fn from(value: Recipient) -> Self {
+        value.to_string()
+    }
+}
+
+/// A secret key that unlocks the diaries shared with its [`Recipient`].
+pub struct Identity(StaticSecret);
+impl Identity {
+    const PREFIX: &'static str = "JOURNALR-SK-";
+    pub fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut *bytes);
+        Self(StaticSecret::from(*bytes))
+    }
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+    /// Read an identity file. Lines starting with `#` are comments.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a journalr identity file");
+        let line = contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(invalid)?;
+        let bytes = line
+            .strip_prefix(Self::PREFIX)
+            .and_then(|key| BASE32_NOPAD.decode(key.as_bytes()).ok())
+            .map(Zeroizing::new)
+            .ok_or_else(invalid)?;
+        let bytes = Zeroizing::new(<[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| invalid())?);
+        Ok(Self(StaticSecret::from(*bytes)))
+    }
+    /// Write the identity to a new file only the current user can read.
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        let secret = Zeroizing::new(BASE32_NOPAD.encode(self.0.as_bytes()));
+        writeln!(file, "# journalr identity")?;
+        writeln!(file, "# public key: {}", self.recipient())?;
+        writeln!(file, "{}{}", Self::PREFIX, *secret)
+    }
+}
+
+/// Everything needed to write a diary file again after it was opened.
+pub enum DiaryKey {
+    /// A plaintext file, which needs no key at all.
+    Plaintext,
+    /// A legacy cocoon file, which is keyed by the password directly.
+    Legacy(Password),
+    Current {
+        header: Header,
+        content_key: KeyBytes,
+    },
+}
+impl DiaryKey {
+    /// A fresh content key protected by `password` and, if given, `keyfile`.
+    pub fn new(
+        password: &str,
+        kdf: Kdf,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Self, DiaryFromFileError> {
+        let mut content_key = Zeroizing::new([0; KEY_SIZE]);
+        OsRng.fill_bytes(&mut *content_key);
+        let header = Header {
+            password: Some(PasswordSlot::wrap(&content_key, password, kdf, keyfile)?),
+            recovery: None,
+            recipients: Vec::new(),
+        };
+        Ok(Self::Current {
+            header,
+            content_key,
+        })
+    }
+    /// Protect the same content key with a new password, KDF or keyfile, keeping the recovery
+    /// key and recipients.
+    ///
+    /// Legacy keys are upgraded to the current format, and plaintext diaries get encrypted.
+    pub fn rekey(
+        self,
+        password: &str,
+        kdf: Kdf,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Self, DiaryFromFileError> {
+        match self {
+            Self::Plaintext | Self::Legacy(_) => Self::new(password, kdf, keyfile),
+            Self::Current {
+                content_key,
+                mut header,
+            } => {
+                header.password = Some(PasswordSlot::wrap(&content_key, password, kdf, keyfile)?);
+                Ok(Self::Current {
+                    header,
+                    content_key,
+                })
+            }
+        }
+    }
+    fn current(&mut self) -> Result<(&mut Header, &KeyBytes), DiaryFromFileError> {
+        match self {
+            Self::Plaintext => Err(DiaryFromFileError::NotEncrypted),
+            Self::Legacy(_) => Err(DiaryFromFileError::LegacyFormat),
+            Self::Current {
+                header,
+                content_key,
+            } => Ok((header, content_key)),
+        }
+    }
+    /// Generate a recovery key for the diary, replacing any earlier one.
+    ///
+    /// Legacy keys have nowhere to store it and need to be re-keyed first.
+    pub fn add_recovery(&mut self) -> Result<RecoveryKey, DiaryFromFileError> {
+        let (header, content_key) = self.current()?;
+        let recovery = RecoveryKey::generate();
+        let wrapped =
+            MiniCocoon::from_key(&*recovery.wrapping_key(), &seed()).wrap(&**content_key)?;
+        header.recovery = Some(HEXLOWER.encode(&wrapped));
+        Ok(recovery)
+    }
+    /// Let `recipient` open the diary. Returns false if they already could.
+    pub fn add_recipient(&mut self, recipient: Recipient) -> Result<bool, DiaryFromFileError> {
+        let (header, content_key) = self.current()?;
+        if header.recipients.iter().any(|r| r.recipient == recipient) {
+            return Ok(false);
+        }
+        let slot = RecipientSlot::wrap(content_key, recipient)?;
+        header.recipients.push(slot);
+        Ok(true)
+    }
+    /// Stop `recipient` from opening the diary. Returns false if they couldn't.
+    ///
+    /// Fails if that would leave no way to open it. They may have kept the content key, so
+    /// follow up with [`rotate`](Self::rotate) before sealing again.
+    pub fn remove_recipient(&mut self, recipient: Recipient) -> Result<bool, DiaryFromFileError> {
+        let (header, _) = self.current()?;
+        let before = header.recipients.len();
+        let left = header
+            .recipients
+            .iter()
+            .filter(|r| r.recipient != recipient)
+            .count();
+        if header.password.is_none() && header.recovery.is_none() && left == 0 {
+            return Err(DiaryFromFileError::NoWayToUnlock);
+        }
+        header.recipients.retain(|r| r.recipient != recipient);
+        Ok(left != before)
+    }
+    /// Only let recipients open the diary. Fails if it has none. Like after
+    /// [`remove_recipient`](Self::remove_recipient), [`rotate`](Self::rotate) the key next.
+    pub fn remove_password(&mut self) -> Result<(), DiaryFromFileError> {
+        let (header, _) = self.current()?;
+        if header.recipients.is_empty() {
+            return Err(DiaryFromFileError::NoWayToUnlock);
+        }
+        header.password = None;
+        Ok(())
+    }
+    /// Replace the content key with a fresh one, so whoever could unwrap the old one cannot
+    /// open what is sealed from now on.
+    ///
+    /// The password slot is wrapped again with `password` and `keyfile`, which have to unlock
+    /// it, and the remaining recipients keep access. The old recovery key cannot be carried over
+    /// without knowing it, so a diary that had one gets a new one, which is returned.
+    pub fn rotate(
+        &mut self,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Option<RecoveryKey>, DiaryFromFileError> {
+        let (header, _) = self.current()?;
+        let mut content_key = Zeroizing::new([0; KEY_SIZE]);
+        OsRng.fill_bytes(&mut *content_key);
+        let password = match &header.password {
+            Some(slot) => {
+                slot.unwrap(password, keyfile)?;
+                let keyfile = keyfile.filter(|_| slot.keyfile.is_some());
+                Some(PasswordSlot::wrap(
+                    &content_key,
+                    password,
+                    slot.kdf,
+                    keyfile,
+                )?)
+            }
+            None => None,
+        };
+        let recipients = header
+            .recipients
+            .iter()
+            .map(|slot| RecipientSlot::wrap(&content_key, slot.recipient))
+            .collect::<Result<_, _>>()?;
+        let had_recovery = header.recovery.is_some();
+        *self = Self::Current {
+            header: Header {
+                password,
+                recovery: None,
+                recipients,
+            },
+            content_key,
+        };
+        had_recovery.then(|| self.add_recovery()).transpose()
+    }
+    pub fn recipients(&self) -> Vec<Recipient> {
+        match self {
+            Self::Plaintext | Self::Legacy(_) => Vec::new(),
+            Self::Current { header, .. } => header.recipients.iter().map(|r| r.recipient).collect(),
+        }
+    }
+    /// The KDF protecting this key, or `None` for cocoon's built-in PBKDF2 and diaries without
+    /// a password.
+    pub fn kdf(&self) -> Option<Kdf> {
+        match self {
+            Self::Plaintext | Self::Legacy(_) => None,
+            Self::Current { header, .. } => header.password.as_ref().map(|p| p.kdf),
+        }
+    }
+    /// Whether the diary can be opened with a password.
+    pub fn has_password(&self) -> bool {
+        match self {
+            Self::Plaintext => false,
+            Self::Legacy(_) => true,
+            Self::Current { header, .. } => header.password.is_some(),
+        }
+    }
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, Self::Plaintext)
+    }
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Self::Legacy(_))
+    }
+    /// Whether opening the diary again needs a keyfile.
+    pub fn needs_keyfile(&self) -> bool {
+        matches!(self, Self::Current { header, .. }
+            if header.password.as_ref().is_some_and(|p| p.keyfile.is_some()))
+    }
+    /// Decrypt the file contents in `reader`. `keyfile` is ignored if the diary doesn't need one.
+    pub fn open(
+        reader: &mut impl Read,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<(Zeroizing<Vec<u8>>, Self), DiaryFromFileError> {
+        match Format::read(reader)? {
+            Format::Plaintext(prefix) => {
+                return Ok((read_plaintext(prefix, reader)?, Self::Plaintext))
+            }
+            Format::Legacy(prefix) => {
+                let cocoon = Cocoon::new(password.as_bytes());
+                let bytes = cocoon.parse(&mut prefix.as_slice().chain(reader))?;
+                return Ok((
+                    Zeroizing::new(bytes),
+                    Self::Legacy(Password::new(password, false)),
+                ));
+            }
+            Format::Current => (),
+        }
+        Self::open_current(reader, |header| match &header.password {
+            Some(slot) => slot.unwrap(password, keyfile),
+            None => Err(DiaryFromFileError::IdentityRequired),
+        })
+    }
+    /// Decrypt the file contents in `reader` if that needs no password: plaintext diaries, and
+    /// legacy ones whose password is empty. Other diaries fail as if the password were wrong.
+    pub fn open_unprotected(
+        reader: &mut impl Read,
+    ) -> Result<(Zeroizing<Vec<u8>>, Self), DiaryFromFileError> {
+        match Format::read(reader)? {
+            Format::Plaintext(prefix) => Ok((read_plaintext(prefix, reader)?, Self::Plaintext)),
+            Format::Legacy(prefix) => Self::open(&mut prefix.as_slice().chain(reader), "", None),
+            Format::Current => Self::open_current(reader, |header| match header.password {
+                Some(_) => Err(DiaryFromFileError::WrongPassword),
+                None => Err(DiaryFromFileError::IdentityRequired),
+            }),
+        }
+    }
+    /// Decrypt the file contents in `reader` as one of its recipients.
+    pub fn open_as(
+        reader: &mut impl Read,
+        identity: &Identity,
+    ) -> Result<(Zeroizing<Vec<u8>>, Self), DiaryFromFileError> {
+        match Format::read(reader)? {
+            Format::Plaintext(prefix) => {
+                return Ok((read_plaintext(prefix, reader)?, Self::Plaintext))
+            }
+            Format::Legacy(_) => return Err(DiaryFromFileError::NotARecipient),
+            Format::Current => (),
+        }
+        Self::open_current(reader, |header| {
+            let recipient = identity.recipient();
+            header
+                .recipients
+                .iter()
+                .find(|slot| slot.recipient == recipient)
+                .ok_or(DiaryFromFileError::NotARecipient)?
+                .unwrap(identity)
+        })
+    }
+    /// Decrypt the file contents in `reader` with its recovery key.
+    pub fn recover(
+        reader: &mut impl Read,
+        recovery: &RecoveryKey,
+    ) -> Result<(Zeroizing<Vec<u8>>, Self), DiaryFromFileError> {
+        match Format::read(reader)? {
+            Format::Plaintext(prefix) => {
+                return Ok((read_plaintext(prefix, reader)?, Self::Plaintext))
+            }
+            Format::Legacy(_) => return Err(DiaryFromFileError::NoRecoveryKey),
+            Format::Current => (),
+        }
+        Self::open_current(reader, |header| {
+            let wrapped = header
+                .recovery
+                .as_deref()
+                .ok_or(DiaryFromFileError::NoRecoveryKey)?;
+            let key = recovery.wrapping_key();
+            content_key(MiniCocoon::from_key(&*key, &seed()).unwrap(&decode(wrapped)?)?)
+        })
+    }
+    fn open_current(
+        reader: &mut impl Read,
+        unwrap: impl FnOnce(&Header) -> Result<KeyBytes, DiaryFromFileError>,
+    ) -> Result<(Zeroizing<Vec<u8>>, Self), DiaryFromFileError> {
+        let header = Header::read(reader)?;
+        let content_key = unwrap(&header)?;
+        let bytes = MiniCocoon::from_key(&*content_key, &seed()).parse(reader)?;
+        Ok((
+            Zeroizing::new(bytes),
+            Self::Current {
+                header,
+                content_key,
+            },
+        ))
+    }
+    /// Tells apart the keys files are sealed with, without revealing them.
+    pub(crate) fn fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"journalr key fingerprint");
+        match self {
+            Self::Plaintext => (),
+            Self::Legacy(password) => hasher.update(password.as_bytes()),
+            Self::Current { content_key, .. } => hasher.update(**content_key),
+        }
+        hasher.finalize().into()
+    }
+    /// Serialize `value` for [`seal`](Self::seal), readably if it will be stored as plaintext.
+    pub fn serialize(&self, value: &impl Serialize) -> serde_json::Result<Vec<u8>> {
+        match self {
+            Self::Plaintext => serde_json::to_vec_pretty(value),
+            _ => serde_json::to_vec(value),
+        }
+    }
+    /// Decrypt a file sealed with this key without unwrapping the content key again.
+    pub fn unseal(&self, reader: &mut impl Read) -> Result<Zeroizing<Vec<u8>>, DiaryFromFileError> {
+        match (self, Format::read(reader)?) {
+            (Self::Plaintext, Format::Plaintext(prefix)) => read_plaintext(prefix, reader),
+            (Self::Current { content_key, .. }, Format::Current) => {
+                Header::read(reader)?;
+                let cocoon = MiniCocoon::from_key(&**content_key, &seed());
+                Ok(Zeroizing::new(cocoon.parse(reader)?))
+            }
+            (Self::Legacy(_), _) => Err(DiaryFromFileError::LegacyFormat),
+            _ => Err(DiaryFromFileError::InvalidFormat),
+        }
+    }
+    /// Encrypt `data` in place and write it as a diary file.
+    pub fn seal(&self, data: Vec<u8>, writer: &mut impl Write) -> Result<(), DiaryFromFileError> {
+        match self {
+            Self::Plaintext => writer.write_all(&Zeroizing::new(data))?,
+            Self::Legacy(password) => Cocoon::new(password.as_bytes()).dump(data, writer)?,
+            Self::Current {
+                header,
+                content_key,
+            } => {
+                writer.write_all(MAGIC)?;
+                header.write(writer)?;
+                MiniCocoon::from_key(&**content_key, &seed()).dump(data, writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Header {
+    fn read(reader: &mut impl Read) -> Result<Self, DiaryFromFileError> {
+        let mut len = [0; 4];
+        reader
+            .read_exact(&mut len)
+            .map_err(|_| DiaryFromFileError::InvalidFormat)?;
+        let mut json = Vec::new();
+        reader
+            .take(u32::from_le_bytes(len).into())
+            .read_to_end(&mut json)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let json = serde_json::to_vec(self)?;
+        writer.write_all(&(json.len() as u32).to_le_bytes())?;
+        writer.write_all(&json)
+    }
+}
+impl PasswordSlot {
+    fn wrap(
+        content_key: &KeyBytes,
+        password: &str,
+        kdf: Kdf,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Self, DiaryFromFileError> {
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = kdf.derive(password.as_bytes(), &salt, keyfile)?;
+        let wrapped = MiniCocoon::from_key(&*key, &seed()).wrap(&**content_key)?;
+        Ok(Self {
+            kdf,
+            salt: HEXLOWER.encode(&salt),
+            wrapped_key: HEXLOWER.encode(&wrapped),
+            keyfile: keyfile.map(|k| k.check(&salt)),
+        })
+    }
+    fn unwrap(
+        &self,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<KeyBytes, DiaryFromFileError> {
+        let salt = decode(&self.salt)?;
+        let keyfile = match (&self.keyfile, keyfile) {
+            (None, _) => None,
+            (Some(_), None) => return Err(DiaryFromFileError::KeyfileRequired),
+            (Some(check), Some(keyfile)) if *check != keyfile.check(&salt) => {
+                return Err(DiaryFromFileError::WrongKeyfile)
+            }
+            (Some(_), keyfile) => keyfile,
+        };
+        let key = self.kdf.derive(password.as_bytes(), &salt, keyfile)?;
+        content_key(MiniCocoon::from_key(&*key, &seed()).unwrap(&decode(&self.wrapped_key)?)?)
+    }
+}
+impl RecipientSlot {
+    fn wrap(content_key: &KeyBytes, recipient: Recipient) -> Result<Self, DiaryFromFileError> {
+        let ephemeral = Identity::generate();
+        let key = agree(&ephemeral, recipient, ephemeral.recipient(), recipient)?;
+        let wrapped = MiniCocoon::from_key(&*key, &seed()).wrap(&**content_key)?;
+        Ok(Self {
+            recipient,
+            ephemeral: HEXLOWER.encode(ephemeral.recipient().0.as_bytes()),
+            wrapped_key: HEXLOWER.encode(&wrapped),
+        })
+    }
+    fn unwrap(&self, identity: &Identity) -> Result<KeyBytes, DiaryFromFileError> {
+        let ephemeral = <[u8; 32]>::try_from(decode(&self.ephemeral)?)
+            .map_err(|_| DiaryFromFileError::InvalidFormat)?;
+        let ephemeral = Recipient(ephemeral.into());
+        let key = agree(identity, ephemeral, ephemeral, self.recipient)?;
+        content_key(MiniCocoon::from_key(&*key, &seed()).unwrap(&decode(&self.wrapped_key)?)?)
+    }
+}
+/// The key wrapping the content key for `recipient`, from the secret of one side of the
+/// exchange and the public key of the other.
+fn agree(
+    secret: &Identity,
+    public: Recipient,
+    ephemeral: Recipient,
+    recipient: Recipient,
+) -> Result<KeyBytes, DiaryFromFileError> {
+    let shared = secret.0.diffie_hellman(&public.0);
+    if !shared.was_contributory() {
+        return Err(DiaryFromFileError::InvalidFormat);
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(b"journalr recipient");
+    hasher.update(shared.as_bytes());
+    hasher.update(ephemeral.0.as_bytes());
+    hasher.update(recipient.0.as_bytes());
+    Ok(Zeroizing::new(hasher.finalize().into()))
+}
+
+/// Which kind of file a diary is, with the bytes read to find out unless it is [`MAGIC`].
+enum Format {
+    Plaintext(Vec<u8>),
+    Legacy(Vec<u8>),
+    Current,
+}
+impl Format {
+    fn read(reader: &mut impl Read) -> Result<Self, DiaryFromFileError> {
+        let mut prefix = Vec::new();
+        reader.take(MAGIC.len() as u64).read_to_end(&mut prefix)?;
+        Ok(match prefix.iter().find(|b| !b.is_ascii_whitespace()) {
+            _ if prefix == MAGIC => Self::Current,
+            Some(b'{') => Self::Plaintext(prefix),
+            _ => Self::Legacy(prefix),
+        })
+    }
+}
+fn read_plaintext(
+    prefix: Vec<u8>,
+    reader: &mut impl Read,
+) -> Result<Zeroizing<Vec<u8>>, DiaryFromFileError> {
+    let mut bytes = Zeroizing::new(prefix);
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+fn content_key(unwrapped: Vec<u8>) -> Result<KeyBytes, DiaryFromFileError> {
+    let unwrapped = Zeroizing::new(unwrapped);
+    let mut key = Zeroizing::new([0; KEY_SIZE]);
+    if unwrapped.len() != KEY_SIZE {
+        return Err(DiaryFromFileError::InvalidFormat);
+    }
+    key.copy_from_slice(&unwrapped);
+    Ok(key)
+}
+fn decode(hex: &str) -> Result<Vec<u8>, DiaryFromFileError> {
+    HEXLOWER
+        .decode(hex.as_bytes())
+        .map_err(|_| DiaryFromFileError::InvalidFormat)
+}
+/// Seed for the nonce generator inside a [`MiniCocoon`].
+fn seed() -> [u8; 32] {
+    let mut seed = [0; 32];
+    OsRng.fill_bytes(&mut seed);
+    seed
+}
+
+#[test]
+fn opens_current_and_legacy_files() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let mut file = Vec::new();
+    let key = DiaryKey::new("pw", kdf, None).unwrap();
+    key.seal(b"current".to_vec(), &mut file).unwrap();
+    assert!(matches!(
+        DiaryKey::open(&mut file.as_slice(), "wrong", None),
+        Err(DiaryFromFileError::WrongPassword)
+    ));
+    let (data, key) = DiaryKey::open(&mut file.as_slice(), "pw", None).unwrap();
+    assert_eq!(data.as_slice(), b"current");
+    assert_eq!(key.kdf(), Some(kdf));
+
+    let mut legacy = Vec::new();
+    Cocoon::new(b"pw")
+        .dump(b"legacy".to_vec(), &mut legacy)
+        .unwrap();
+    let (data, key) = DiaryKey::open(&mut legacy.as_slice(), "pw", None).unwrap();
+    assert_eq!(data.as_slice(), b"legacy");
+    let key = key.rekey("new", kdf, None).unwrap();
+    let mut file = Vec::new();
+    key.seal(b"legacy".to_vec(), &mut file).unwrap();
+    let (data, _) = DiaryKey::open(&mut file.as_slice(), "new", None).unwrap();
+    assert_eq!(data.as_slice(), b"legacy");
+}
+
+#[test]
+fn keyfile_is_required_and_checked() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let keyfile = Keyfile(Zeroizing::new([1; KEY_SIZE]));
+    let other = Keyfile(Zeroizing::new([2; KEY_SIZE]));
+    let mut file = Vec::new();
+    let key = DiaryKey::new("pw", kdf, Some(&keyfile)).unwrap();
+    assert!(key.needs_keyfile());
+    key.seal(b"secret".to_vec(), &mut file).unwrap();
+    let open = |password, keyfile| {
+        DiaryKey::open(&mut file.as_slice(), password, keyfile).map(|(data, _)| data.to_vec())
+    };
+    assert_eq!(open("pw", None), Err(DiaryFromFileError::KeyfileRequired));
+    assert_eq!(
+        open("pw", Some(&other)),
+        Err(DiaryFromFileError::WrongKeyfile)
+    );
+    assert_eq!(
+        open("wrong", Some(&keyfile)),
+        Err(DiaryFromFileError::WrongPassword)
+    );
+    assert_eq!(open("pw", Some(&keyfile)).unwrap(), b"secret");
+}
+
+#[test]
+fn recovery_key_survives_rekey() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let mut key = DiaryKey::new("pw", kdf, None).unwrap();
+    let recovery = key.add_recovery().unwrap();
+    let key = key.rekey("new", kdf, None).unwrap();
+    let mut file = Vec::new();
+    key.seal(b"secret".to_vec(), &mut file).unwrap();
+    let typed = recovery.encode().to_lowercase().replace('-', " ");
+    let recovery = RecoveryKey::parse(&typed).unwrap();
+    let (data, _) = DiaryKey::recover(&mut file.as_slice(), &recovery).unwrap();
+    assert_eq!(data.as_slice(), b"secret");
+    let other = RecoveryKey::generate();
+    assert!(DiaryKey::recover(&mut file.as_slice(), &other).is_err());
+}
+
+#[test]
+fn recipients_open_shared_diary() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let (alice, bob, eve) = (
+        Identity::generate(),
+        Identity::generate(),
+        Identity::generate(),
+    );
+    let mut key = DiaryKey::new("pw", kdf, None).unwrap();
+    assert!(key.add_recipient(alice.recipient()).unwrap());
+    assert!(key.add_recipient(bob.recipient()).unwrap());
+    assert!(!key.add_recipient(bob.recipient()).unwrap());
+    key.remove_password().unwrap();
+    let mut file = Vec::new();
+    key.seal(b"shared".to_vec(), &mut file).unwrap();
+    for identity in [&alice, &bob] {
+        let (data, _) = DiaryKey::open_as(&mut file.as_slice(), identity).unwrap();
+        assert_eq!(data.as_slice(), b"shared");
+    }
+    assert!(matches!(
+        DiaryKey::open_as(&mut file.as_slice(), &eve),
+        Err(DiaryFromFileError::NotARecipient)
+    ));
+    assert!(matches!(
+        DiaryKey::open(&mut file.as_slice(), "pw", None),
+        Err(DiaryFromFileError::IdentityRequired)
+    ));
+    let recipient = alice.recipient().to_string().parse::<Recipient>().unwrap();
+    assert!(key.remove_recipient(recipient).unwrap());
+    assert!(matches!(
+        key.remove_recipient(bob.recipient()),
+        Err(DiaryFromFileError::NoWayToUnlock)
+    ));
+    assert_eq!(key.recipients(), vec![bob.recipient()]);
+    let mut file = Vec::new();
+    key.seal(b"shared".to_vec(), &mut file).unwrap();
+    assert!(DiaryKey::open_as(&mut file.as_slice(), &bob).is_ok());
+}
+
+#[test]
+fn plaintext_files_open_without_a_password() {
+    let mut file = Vec::new();
+    DiaryKey::Plaintext
+        .seal(b"{\"entries\":{}}".to_vec(), &mut file)
+        .unwrap();
+    let (data, key) = DiaryKey::open(&mut file.as_slice(), "anything", None).unwrap();
+    assert_eq!(data.as_slice(), b"{\"entries\":{}}");
+    assert!(key.is_plaintext());
+    assert!(DiaryKey::open_unprotected(&mut file.as_slice()).is_ok());
+
+    let mut legacy = Vec::new();
+    Cocoon::new(b"")
+        .dump(b"legacy".to_vec(), &mut legacy)
+        .unwrap();
+    assert!(DiaryKey::open_unprotected(&mut legacy.as_slice()).is_ok());
+    let key = DiaryKey::new("", Kdf::argon2id(64, 1, 1).unwrap(), None).unwrap();
+    let mut current = Vec::new();
+    key.seal(b"current".to_vec(), &mut current).unwrap();
+    assert!(matches!(
+        DiaryKey::open_unprotected(&mut current.as_slice()),
+        Err(DiaryFromFileError::WrongPassword)
+    ));
+}
+#[test]
+fn header_kdf_settings_are_capped() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let mut file = Vec::new();
+    let key = DiaryKey::new("pw", kdf, None).unwrap();
+    key.seal(b"text".to_vec(), &mut file).unwrap();
+    let mut header = &file[MAGIC.len()..];
+    let mut header = Header::read(&mut header).unwrap();
+    header.password.as_mut().unwrap().kdf = Kdf::Argon2id {
+        memory: u32::MAX,
+        iterations: 1,
+        parallelism: 1,
+    };
+    let mut rest = &file[MAGIC.len()..];
+    Header::read(&mut rest).unwrap();
+    let mut forged = MAGIC.to_vec();
+    header.write(&mut forged).unwrap();
+    forged.extend_from_slice(rest);
+    assert!(matches!(
+        DiaryKey::open(&mut forged.as_slice(), "pw", None),
+        Err(DiaryFromFileError::InvalidFormat)
+    ));
+    assert!(Kdf::argon2id(64, Kdf::MAX_ITERATIONS + 1, 1).is_err());
+}
+#[test]
+fn removing_a_recipient_rotates_the_content_key() {
+    let kdf = Kdf::argon2id(64, 1, 1).unwrap();
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+    let mut key = DiaryKey::new("pw", kdf, None).unwrap();
+    key.add_recipient(alice.recipient()).unwrap();
+    key.add_recipient(bob.recipient()).unwrap();
+    let old_recovery = key.add_recovery().unwrap();
+    let old_content_key = match &key {
+        DiaryKey::Current { content_key, .. } => content_key.clone(),
+        _ => unreachable!(),
+    };
+    key.remove_recipient(bob.recipient()).unwrap();
+    assert!(key.rotate("wrong", None).is_err());
+    let recovery = key.rotate("pw", None).unwrap().unwrap();
+    assert!(
+        matches!(&key, DiaryKey::Current { content_key, .. } if *content_key != old_content_key)
+    );
+    let mut file = Vec::new();
+    key.seal(b"text".to_vec(), &mut file).unwrap();
+    assert!(DiaryKey::open(&mut file.as_slice(), "pw", None).is_ok());
+    assert!(DiaryKey::open_as(&mut file.as_slice(), &alice).is_ok());
+    assert!(DiaryKey::open_as(&mut file.as_slice(), &bob).is_err());
+    assert!(DiaryKey::recover(&mut file.as_slice(), &recovery).is_ok());
+    assert!(DiaryKey::recover(&mut file.as_slice(), &old_recovery).is_err());
+}
@@ -3,7 +3,7 @@ use std::ops::{Deref, DerefMut};
 use chrono::prelude::*;
 pub use chrono::ParseError;
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Copy)]
 #[serde(into = "String", try_from = "String")]
 pub struct Date {
     inner: NaiveDate,
@@ -1,7 +1,17 @@
-use crate::date::Date;
-use cocoon::Cocoon;
+use crate::{
+    crypto::{DiaryKey, Identity, Kdf, Keyfile},
+    date::Date,
+    secret::Zeroize,
+};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, fs::File, io};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{self, Read, Write},
+};
+#[cfg(feature = "tui")]
 use tui_textarea::TextArea;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -11,6 +21,23 @@ pub enum DiaryFromFileError {
     OutOfRangeSize,
     NotFound,
     NotAccessible,
+    /// The diary needs a keyfile and none was given.
+    KeyfileRequired,
+    WrongKeyfile,
+    NoRecoveryKey,
+    /// The diary is still in the format from before key wrapping.
+    LegacyFormat,
+    /// The diary is stored as plaintext, so it has no keys to change.
+    NotEncrypted,
+    /// A diary was to be created where one already exists.
+    AlreadyExists,
+    /// The diary has no password and is opened with an identity file.
+    IdentityRequired,
+    NotARecipient,
+    /// Removing the last password or recipient would make the diary impossible to open.
+    NoWayToUnlock,
+    /// The diary was changed by something else since it was loaded or last saved.
+    ChangedOnDisk,
 }
 impl From<cocoon::Error> for DiaryFromFileError {
     fn from(value: cocoon::Error) -> Self {
@@ -45,6 +72,16 @@ impl Display for DiaryFromFileError {
             Self::NotFound => "File does not exist",
             Self::WrongPassword => "Wrong Password",
             Self::OutOfRangeSize => "File has invalid size",
+            Self::KeyfileRequired => "This diary needs its keyfile",
+            Self::WrongKeyfile => "Wrong Keyfile",
+            Self::NoRecoveryKey => "This diary has no recovery key",
+            Self::LegacyFormat => "This diary uses the old file format; run `journalr rekey` first",
+            Self::NotEncrypted => "This diary is not encrypted; run `journalr convert` first",
+            Self::AlreadyExists => "A diary already exists there",
+            Self::IdentityRequired => "This diary is opened with an identity file",
+            Self::NotARecipient => "This identity cannot open the diary",
+            Self::NoWayToUnlock => "Nothing would be left to open the diary with",
+            Self::ChangedOnDisk => "The diary was changed by something else meanwhile",
         };
         write!(f, "{message}")
     }
@@ -53,6 +90,45 @@ impl std::error::Error for DiaryFromFileError {}
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Diary {
     pub entries: HashMap<Date, String>,
+    /// Deleted entries, most recently deleted last.
+    #[serde(default)]
+    pub trash: Vec<TrashedEntry>,
+    /// Earlier versions of each entry, oldest first.
+    #[serde(default)]
+    pub history: HashMap<Date, Vec<Revision>>,
+}
+/// The text an entry had before a save replaced it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Revision {
+    pub text: String,
+    /// When this text was overwritten.
+    pub replaced: DateTime<Utc>,
+}
+impl Zeroize for Revision {
+    fn zeroize(&mut self) {
+        self.text.zeroize();
+    }
+}
+/// An entry that was deleted but can still be restored.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TrashedEntry {
+    pub date: Date,
+    pub text: String,
+    pub deleted: DateTime<Utc>,
+}
+impl TrashedEntry {
+    pub fn new(date: Date, text: String) -> Self {
+        Self {
+            date,
+            text,
+            deleted: Utc::now(),
+        }
+    }
+}
+impl Zeroize for TrashedEntry {
+    fn zeroize(&mut self) {
+        self.text.zeroize();
+    }
 }
 impl Default for Diary {
     fn default() -> Self {
@@ -60,49 +136,297 @@ impl Default for Diary {
     }
 }
 impl Diary {
+    /// Number of earlier versions kept for each entry unless configured otherwise.
+    pub const DEFAULT_MAX_REVISIONS: usize = 20;
     pub fn new() -> Self {
         Self {
             entries: HashMap::new(),
+            trash: Vec::new(),
+            history: HashMap::new(),
         }
     }
+    /// Add a revision for every entry whose text differs from `previous`, keeping at most
+    /// `keep` revisions per entry.
+    pub fn record_revisions(&mut self, previous: &HashMap<Date, String>, keep: usize) {
+        let now = Utc::now();
+        for (date, text) in &self.entries {
+            match previous.get(date) {
+                Some(old) if old != text => {
+                    self.history.entry(*date).or_default().push(Revision {
+                        text: old.clone(),
+                        replaced: now,
+                    });
+                }
+                _ => (),
+            }
+        }
+        for revisions in self.history.values_mut() {
+            let excess = revisions.len().saturating_sub(keep);
+            revisions.drain(..excess).for_each(|mut r| r.zeroize());
+        }
+        self.history.retain(|_, revisions| !revisions.is_empty());
+    }
+    /// Permanently drop trashed entries deleted more than `retention` ago.
+    pub fn purge_trash(&mut self, retention: TimeDelta) {
+        let cutoff = Utc::now() - retention;
+        self.trash.retain_mut(|t| {
+            let keep = t.deleted > cutoff;
+            if !keep {
+                t.zeroize();
+            }
+            keep
+        });
+    }
     pub fn read_jrnl(path: &str, password: &str) -> Result<Self, DiaryFromFileError> {
-        let cocoon = Cocoon::new(password.as_bytes());
-        let bytes = cocoon.parse(&mut File::open(path)?)?;
-        let string = String::from_utf8_lossy(bytes.as_slice()).into_owned();
-        Ok(serde_json::from_str(&string)?)
+        Ok(Self::open(path, password, None)?.0)
+    }
+    /// Decrypt the diary at `path`, also returning the key to save it with.
+    pub fn open(
+        path: &str,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<(Self, DiaryKey), DiaryFromFileError> {
+        Self::open_from(&mut File::open(path)?, password, keyfile)
+    }
+    /// Decrypt a diary from anything holding the contents of a diary file.
+    pub fn read_from(
+        reader: &mut impl Read,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Self, DiaryFromFileError> {
+        Ok(Self::open_from(reader, password, keyfile)?.0)
     }
+    pub fn open_from(
+        reader: &mut impl Read,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<(Self, DiaryKey), DiaryFromFileError> {
+        let (bytes, key) = DiaryKey::open(reader, password, keyfile)?;
+        Ok((serde_json::from_slice(&bytes)?, key))
+    }
+    /// Decrypt a diary shared with `identity`.
+    pub fn open_as(
+        reader: &mut impl Read,
+        identity: &Identity,
+    ) -> Result<(Self, DiaryKey), DiaryFromFileError> {
+        let (bytes, key) = DiaryKey::open_as(reader, identity)?;
+        Ok((serde_json::from_slice(&bytes)?, key))
+    }
+    /// Write the diary to a new file protected by `password`, with default KDF settings.
     pub fn write_to(&self, path: &str, password: &str) -> Result<(), DiaryFromFileError> {
-        let mut cocoon = Cocoon::new(password.as_bytes());
-        cocoon.dump(
-            serde_json::to_string(self)?.into_bytes(),
-            &mut File::create(path)?,
-        )?;
-        Ok(())
+        self.write_with(path, &DiaryKey::new(password, Kdf::default(), None)?)
+    }
+    /// Write the diary encrypted with `key`, usually the one it was opened with.
+    pub fn write_with(&self, path: &str, key: &DiaryKey) -> Result<(), DiaryFromFileError> {
+        self.write_into(&mut File::create(path)?, key)
+    }
+    pub fn write_into(
+        &self,
+        writer: &mut impl Write,
+        key: &DiaryKey,
+    ) -> Result<(), DiaryFromFileError> {
+        // `seal` encrypts the buffer in place, so no plaintext copy outlives this call.
+        key.seal(key.serialize(self)?, writer)
+    }
+}
+/// Result of [`Diary::merge`].
+pub struct Merge {
+    /// The merged diary. Entries listed in `conflicts` hold both sides between conflict markers.
+    pub diary: Diary,
+    /// Dates edited differently on both sides, with the text from each side.
+    pub conflicts: Vec<Conflict>,
+}
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub date: Date,
+    /// `None` if the entry was deleted on that side.
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+impl Conflict {
+    /// Both sides in one text, git style.
+    pub fn with_markers(&self, ours_name: &str, theirs_name: &str) -> String {
+        format!(
+            "<<<<<<< {ours_name}\n{}\n=======\n{}\n>>>>>>> {theirs_name}",
+            self.ours.as_deref().unwrap_or_default(),
+            self.theirs.as_deref().unwrap_or_default()
+        )
     }
 }
+impl Diary {
+    /// Merge two diverged copies of a diary, date by date.
+    ///
+    /// A date changed on only one side since `base` takes that side. Without a base, an entry
+    /// present on only one side is kept. Dates changed on both sides become conflicts, and are
+    /// written with conflict markers labelled `names`. Trash and history are combined.
+    pub fn merge(base: Option<&Diary>, ours: &Diary, theirs: &Diary, names: (&str, &str)) -> Merge {
+        let mut diary = Diary::new();
+        let mut conflicts = Vec::new();
+        let mut dates = ours
+            .entries
+            .keys()
+            .chain(theirs.entries.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        dates.sort();
+        dates.dedup();
+        for date in dates {
+            let o = ours.entries.get(&date);
+            let t = theirs.entries.get(&date);
+            let b = base.map(|b| b.entries.get(&date));
+            let merged = match (o, t, b) {
+                (o, t, _) if o == t => o,
+                (o, t, Some(b)) if o == b => t,
+                (o, t, Some(b)) if t == b => o,
+                (Some(o), None, None) => Some(o),
+                (None, Some(t), None) => Some(t),
+                (o, t, _) => {
+                    let conflict = Conflict {
+                        date,
+                        ours: o.cloned(),
+                        theirs: t.cloned(),
+                    };
+                    diary
+                        .entries
+                        .insert(date, conflict.with_markers(names.0, names.1));
+                    conflicts.push(conflict);
+                    continue;
+                }
+            };
+            if let Some(text) = merged {
+                diary.entries.insert(date, text.clone());
+            }
+        }
+        diary.trash = ours.trash.clone();
+        for t in &theirs.trash {
+            if !diary
+                .trash
+                .iter()
+                .any(|o| o.date == t.date && o.deleted == t.deleted)
+            {
+                diary.trash.push(t.clone());
+            }
+        }
+        diary.trash.sort_by_key(|t| t.deleted);
+        diary.history = ours.history.clone();
+        for (date, revisions) in &theirs.history {
+            let merged = diary.history.entry(*date).or_default();
+            for r in revisions {
+                if !merged
+                    .iter()
+                    .any(|o| o.replaced == r.replaced && o.text == r.text)
+                {
+                    merged.push(r.clone());
+                }
+            }
+            merged.sort_by_key(|r| r.replaced);
+        }
+        Merge { diary, conflicts }
+    }
+}
+impl Zeroize for Diary {
+    fn zeroize(&mut self) {
+        self.entries.values_mut().for_each(Zeroize::zeroize);
+        self.entries.clear();
+        self.trash.iter_mut().for_each(Zeroize::zeroize);
+        self.trash.clear();
+        self.history
+            .values_mut()
+            .flatten()
+            .for_each(Zeroize::zeroize);
+        self.history.clear();
+    }
+}
+impl Drop for Diary {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+#[cfg(feature = "tui")]
 impl From<&HashMap<Date, TextArea<'_>>> for Diary {
     fn from(value: &HashMap<Date, TextArea<'_>>) -> Self {
         Self {
+            // Joined rather than newline-terminated so that reloading a saved entry gives back
+            // the same text instead of growing an empty line on every save.
             entries: value
                 .iter()
-                .map(|(k, v)| {
-                    (
-                        *k,
-                        v.lines()
-                            .iter()
-                            .flat_map(|k| -> [&str; 2] { [k, "\n"] })
-                            .collect::<String>(),
-                    )
-                })
+                .map(|(k, v)| (*k, v.lines().join("\n")))
                 .collect(),
+            trash: Vec::new(),
+            history: HashMap::new(),
         }
     }
 }
+#[cfg(feature = "tui")]
 impl<'a> From<Diary> for HashMap<Date, TextArea<'a>> {
     fn from(val: Diary) -> Self {
         val.entries
-            .into_iter()
-            .map(|(k, v)| (k, TextArea::from(v.split('\n'))))
+            .iter()
+            .map(|(k, v)| (*k, TextArea::from(v.split('\n'))))
             .collect()
     }
 }
+
+#[test]
+fn purge_trash_keeps_recent_entries() {
+    let mut diary = Diary::new();
+    let mut old = TrashedEntry::new(Date::today(), "old".into());
+    old.deleted -= TimeDelta::days(40);
+    diary.trash = vec![old, TrashedEntry::new(Date::today(), "new".into())];
+    diary.purge_trash(TimeDelta::days(30));
+    assert_eq!(diary.trash.len(), 1);
+    assert_eq!(diary.trash[0].text, "new");
+}
+
+#[test]
+fn record_revisions_caps_history() {
+    let date = Date::today();
+    let mut diary = Diary::new();
+    for i in 0..5 {
+        let previous = diary.entries.clone();
+        diary.entries.insert(date, format!("version {i}"));
+        diary.record_revisions(&previous, 3);
+    }
+    let texts: Vec<_> = diary.history[&date]
+        .iter()
+        .map(|r| r.text.as_str())
+        .collect();
+    assert_eq!(texts, ["version 1", "version 2", "version 3"]);
+}
+
+#[test]
+fn merge_takes_one_sided_changes_and_marks_conflicts() {
+    let d = |s: &str| Date::try_from(s).unwrap();
+    let diary = |entries: &[(&str, &str)]| {
+        let mut diary = Diary::new();
+        diary.entries = entries
+            .iter()
+            .map(|(date, text)| (d(date), text.to_string()))
+            .collect();
+        diary
+    };
+    let base = diary(&[
+        ("01-01-2024", "a"),
+        ("02-01-2024", "b"),
+        ("03-01-2024", "c"),
+    ]);
+    let ours = diary(&[
+        ("01-01-2024", "a2"),
+        ("02-01-2024", "b"),
+        ("03-01-2024", "c1"),
+    ]);
+    let theirs = diary(&[
+        ("01-01-2024", "a"),
+        ("03-01-2024", "c2"),
+        ("04-01-2024", "d"),
+    ]);
+    let merge = Diary::merge(Some(&base), &ours, &theirs, ("ours", "theirs"));
+    assert_eq!(merge.diary.entries[&d("01-01-2024")], "a2");
+    assert!(!merge.diary.entries.contains_key(&d("02-01-2024")));
+    assert_eq!(merge.diary.entries[&d("04-01-2024")], "d");
+    assert_eq!(merge.conflicts.len(), 1);
+    assert_eq!(
+        merge.diary.entries[&d("03-01-2024")],
+        "<<<<<<< ours\nc1\n=======\nc2\n>>>>>>> theirs"
+    );
+}
@@ -0,0 +1,57 @@
+//! Where the editor gets its input from: the terminal, or a script of events in tests.
+use std::{collections::VecDeque, io, time::Duration};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+
+pub trait EventSource {
+    /// The next event, waiting at most `timeout` for one.
+    fn next(&mut self, timeout: Duration) -> io::Result<Option<Event>>;
+    /// Whether no more events will come, so the editor should stop waiting for them.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+/// Keys, pastes and resizes from the terminal crossterm is attached to.
+pub struct TerminalEvents;
+impl EventSource for TerminalEvents {
+    fn next(&mut self, timeout: Duration) -> io::Result<Option<Event>> {
+        if event::poll(timeout)? {
+            event::read().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// A fixed sequence of events, handed out one per call without waiting.
+#[derive(Default)]
+pub struct ScriptedEvents {
+    events: VecDeque<Event>,
+}
+impl ScriptedEvents {
+    pub fn new(events: impl IntoIterator<Item = Event>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+        }
+    }
+    pub fn key(mut self, code: KeyCode, modifiers: KeyModifiers) -> Self {
+        self.events
+            .push_back(Event::Key(KeyEvent::new(code, modifiers)));
+        self
+    }
+    /// Each character of `text` as a key press.
+    pub fn type_text(self, text: &str) -> Self {
+        text.chars().fold(self, |events, c| {
+            events.key(KeyCode::Char(c), KeyModifiers::NONE)
+        })
+    }
+}
+impl EventSource for ScriptedEvents {
+    fn next(&mut self, _: Duration) -> io::Result<Option<Event>> {
+        Ok(self.events.pop_front())
+    }
+    fn is_exhausted(&self) -> bool {
+        self.events.is_empty()
+    }
+}
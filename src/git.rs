@@ -0,0 +1,209 @@
+//! Versioning of diary files in a local git repository.
+//!
+//! Everything goes through the `git` executable, and only ever touches the repository the
+//! diary file lives in; nothing is pushed or fetched.
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use chrono::Local;
+
+use crate::date::Date;
+
+#[derive(Debug)]
+pub enum GitError {
+    /// `git` could not be run at all.
+    Unavailable(io::Error),
+    /// The file is not inside a git repository.
+    NoRepository,
+    /// A git command exited unsuccessfully, with its stderr.
+    Failed(String),
+}
+impl From<io::Error> for GitError {
+    fn from(value: io::Error) -> Self {
+        Self::Unavailable(value)
+    }
+}
+impl Display for GitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unavailable(e) => write!(f, "Could not run git: {e}"),
+            Self::NoRepository => write!(f, "The diary is not inside a git repository"),
+            Self::Failed(stderr) => write!(f, "git failed: {}", stderr.trim()),
+        }
+    }
+}
+impl std::error::Error for GitError {}
+
+/// How diary saves are committed.
+#[derive(Debug, Clone)]
+pub struct GitConfig {
+    /// Create a repository next to the diary if it is not already in one.
+    pub init: bool,
+    /// Commit message; `{file}`, `{date}` and `{time}` are replaced by the diary file name,
+    /// the date of the open entry and the current time.
+    pub message: String,
+}
+impl GitConfig {
+    pub const DEFAULT_MESSAGE: &'static str = "journalr: update {file} ({date})";
+    /// Commit the diary at `path` after it was saved while `date` was open.
+    pub fn commit(&self, path: &str, date: Date) -> Result<(), GitError> {
+        let repo = match Repo::discover(path) {
+            Ok(repo) => repo,
+            Err(GitError::NoRepository) if self.init => Repo::init(parent(Path::new(path)))?,
+            Err(e) => return Err(e),
+        };
+        let file = Path::new(path)
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let message = self
+            .message
+            .replace("{file}", &file)
+            .replace("{date}", &date.to_string())
+            .replace("{time}", &Local::now().format("%d-%m-%Y %H:%M").to_string());
+        repo.commit_file(path, &message)
+    }
+}
+impl Default for GitConfig {
+    fn default() -> Self {
+        Self {
+            init: false,
+            message: Self::DEFAULT_MESSAGE.to_owned(),
+        }
+    }
+}
+
+/// A commit that touched the diary file.
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub hash: String,
+    /// Commit date as printed by git, in ISO 8601.
+    pub date: String,
+    pub subject: String,
+}
+
+pub struct Repo {
+    root: PathBuf,
+}
+impl Repo {
+    /// The repository containing the file at `path`.
+    pub fn discover(path: impl AsRef<Path>) -> Result<Self, GitError> {
+        Self::containing_dir(parent(path.as_ref()))
+    }
+    fn containing_dir(dir: &Path) -> Result<Self, GitError> {
+        let out = git(dir, ["rev-parse", "--show-toplevel"])?;
+        if !out.status.success() {
+            return Err(GitError::NoRepository);
+        }
+        let root = String::from_utf8_lossy(&out.stdout).trim().into();
+        Ok(Self { root })
+    }
+    /// Create a repository in `dir`.
+    pub fn init(dir: impl AsRef<Path>) -> Result<Self, GitError> {
+        checked(git(dir.as_ref(), ["init", "--quiet"])?)?;
+        Self::containing_dir(dir.as_ref())
+    }
+    /// `path` relative to the repository root, the way git names it inside commits.
+    fn relative(&self, path: impl AsRef<Path>) -> Result<String, GitError> {
+        let path = path.as_ref().canonicalize()?;
+        let root = self.root.canonicalize()?;
+        path.strip_prefix(root)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .map_err(|_| GitError::NoRepository)
+    }
+    /// Commit the current contents of `path` and nothing else. Does nothing if it is unchanged.
+    pub fn commit_file(&self, path: impl AsRef<Path>, message: &str) -> Result<(), GitError> {
+        let file = self.relative(path)?;
+        checked(git(&self.root, ["add", "--", &file])?)?;
+        let status = checked(git(&self.root, ["status", "--porcelain", "--", &file])?)?;
+        if status.stdout.is_empty() {
+            return Ok(());
+        }
+        checked(git(
+            &self.root,
+            ["commit", "--quiet", "-m", message, "--only", "--", &file],
+        )?)?;
+        Ok(())
+    }
+    /// Commits touching `path`, newest first.
+    pub fn log(&self, path: impl AsRef<Path>) -> Result<Vec<Commit>, GitError> {
+        let file = self.relative(path)?;
+        let out = checked(git(
+            &self.root,
+            ["log", "--format=%H%x09%cI%x09%s", "--", &file],
+        )?)?;
+        Ok(String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, '\t');
+                Some(Commit {
+                    hash: parts.next()?.to_owned(),
+                    date: parts.next()?.to_owned(),
+                    subject: parts.next().unwrap_or_default().to_owned(),
+                })
+            })
+            .collect())
+    }
+    /// The (still encrypted) contents of `path` as of `rev`.
+    pub fn show(&self, rev: &str, path: impl AsRef<Path>) -> Result<Vec<u8>, GitError> {
+        let file = self.relative(path)?;
+        // `--end-of-options` so that a revision starting with `-` is not taken for an option.
+        let object = format!("{rev}:{file}");
+        Ok(checked(git(&self.root, ["show", "--end-of-options", &object])?)?.stdout)
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+fn git<'a>(dir: &Path, args: impl IntoIterator<Item = &'a str>) -> io::Result<Output> {
+    Command::new("git").arg("-C").arg(dir).args(args).output()
+}
+fn checked(out: Output) -> Result<Output, GitError> {
+    if out.status.success() {
+        Ok(out)
+    } else {
+        Err(GitError::Failed(
+            String::from_utf8_lossy(&out.stderr).into_owned(),
+        ))
+    }
+}
+
+#[test]
+fn commits_and_reads_back_diary() {
+    let dir = std::env::temp_dir().join(format!("journalr-git-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("diary.jrnl");
+    let path = path.to_str().unwrap();
+    let mut diary = crate::diary::Diary::new();
+    diary.entries.insert(Date::today(), "first".into());
+    diary.write_to(path, "pw").unwrap();
+    // Commits need an identity, which a test machine may not have configured.
+    Repo::init(&dir).unwrap();
+    for (key, value) in [
+        ("user.name", "journalr"),
+        ("user.email", "journalr@localhost"),
+    ] {
+        checked(git(&dir, ["config", key, value]).unwrap()).unwrap();
+    }
+    GitConfig::default().commit(path, Date::today()).unwrap();
+    let repo = Repo::discover(path).unwrap();
+    let log = repo.log(path).unwrap();
+    assert_eq!(log.len(), 1);
+    assert!(log[0].subject.starts_with("journalr: update diary.jrnl"));
+    let old = repo.show(&log[0].hash, path).unwrap();
+    let old = crate::diary::Diary::read_from(&mut old.as_slice(), "pw", None).unwrap();
+    assert_eq!(old.entries[&Date::today()], "first");
+    assert!(matches!(
+        repo.show("--output=/dev/null", path),
+        Err(GitError::Failed(_))
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,168 @@
+//! Write-ahead journal of unsaved edits, so a crash loses at most the last few seconds of typing.
+//!
+//! The journal is `<diary>.wal` next to the diary. Each record is a [`Snapshot`] of the entries
+//! that differ from the saved diary, sealed with the diary's key and prefixed with its length as
+//! a little endian u32. Each write replaces the journal with one holding only the newest record,
+//! by renaming a temporary file over it, so a crash leaves either the old record or the new one.
+//! Journals written by earlier versions may hold more records; the newest complete one is what
+//! gets replayed. The journal is removed once the diary is saved.
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto::DiaryKey, date::Date, diary::DiaryFromFileError, secret::Zeroize};
+
+/// Unsaved changes relative to the diary as it was last loaded or saved.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Entries that are new or were edited, with their current text.
+    pub changed: HashMap<Date, String>,
+    /// Entries that were deleted.
+    pub removed: Vec<Date>,
+    pub taken: DateTime<Utc>,
+}
+impl Snapshot {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+impl Zeroize for Snapshot {
+    fn zeroize(&mut self) {
+        self.changed.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+impl Journal {
+    /// The journal of the diary called `name`.
+    pub fn next_to(name: &str) -> Self {
+        Self {
+            path: format!(
+                "{}.wal",
+                name.trim_end_matches(['/', std::path::MAIN_SEPARATOR])
+            )
+            .into(),
+        }
+    }
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+    /// Record `snapshot` in place of what was recorded before, flushing it to disk before
+    /// returning.
+    pub fn write(&self, key: &DiaryKey, snapshot: &Snapshot) -> Result<(), DiaryFromFileError> {
+        // Sealing with a legacy key runs the slow password hash every time.
+        if key.is_legacy() {
+            return Err(DiaryFromFileError::LegacyFormat);
+        }
+        let mut record = Vec::new();
+        key.seal(serde_json::to_vec(snapshot)?, &mut record)?;
+        let len = u32::try_from(record.len()).map_err(|_| DiaryFromFileError::OutOfRangeSize)?;
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        let temp = PathBuf::from(temp);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let written = options
+            .open(&temp)
+            .and_then(|mut file| {
+                file.write_all(&len.to_le_bytes())?;
+                file.write_all(&record)?;
+                file.sync_data()
+            })
+            .and_then(|_| fs::rename(&temp, &self.path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        Ok(written?)
+    }
+    /// The newest complete snapshot in the journal, if there is one.
+    pub fn replay(&self, key: &DiaryKey) -> Result<Option<Snapshot>, DiaryFromFileError> {
+        let bytes = match fs::read(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            res => res?,
+        };
+        let Some(mut record) = newest_record(&bytes) else {
+            return Ok(None);
+        };
+        let json = key.unseal(&mut record)?;
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+    /// Forget the recorded changes, once they are saved or discarded.
+    pub fn clear(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+/// The last complete record in `bytes`.
+fn newest_record(bytes: &[u8]) -> Option<&[u8]> {
+    let (mut newest, mut end) = (None, 0);
+    while let Some(len) = bytes.get(end..end + 4) {
+        let start = end + 4;
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let Some(record) = bytes.get(start..start + len) else {
+            break;
+        };
+        newest = Some(record);
+        end = start + len;
+    }
+    newest
+}
+
+#[test]
+fn replays_newest_complete_snapshot() {
+    let journal = Journal::next_to(
+        &std::env::temp_dir()
+            .join(format!("journalr-journal-{}.jrnl", std::process::id()))
+            .to_string_lossy(),
+    );
+    let key = DiaryKey::Plaintext;
+    assert!(journal.replay(&key).unwrap().is_none());
+    let date = Date::today();
+    let snapshot = |text: &str| Snapshot {
+        changed: HashMap::from([(date, text.to_owned())]),
+        removed: Vec::new(),
+        taken: Utc::now(),
+    };
+    // Only the newest record is kept.
+    let single = |journal: &Journal| {
+        let bytes = fs::read(&journal.path).unwrap();
+        newest_record(&bytes).unwrap().len() + 4 == bytes.len()
+    };
+    for text in ["first", "second"] {
+        journal.write(&key, &snapshot(text)).unwrap();
+        assert!(single(&journal));
+    }
+    // A record cut short by a crash, as earlier versions could leave, is skipped.
+    let mut file = OpenOptions::new().append(true).open(&journal.path).unwrap();
+    file.write_all(&100u32.to_le_bytes()).unwrap();
+    file.write_all(b"{\"chan").unwrap();
+    assert_eq!(
+        journal.replay(&key).unwrap().unwrap().changed[&date],
+        "second"
+    );
+    journal.write(&key, &snapshot("third")).unwrap();
+    assert!(single(&journal));
+    assert_eq!(
+        journal.replay(&key).unwrap().unwrap().changed[&date],
+        "third"
+    );
+    journal.clear().unwrap();
+    assert!(!journal.exists());
+}
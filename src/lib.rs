@@ -1,16 +1,56 @@
-use diary::{Diary, DiaryFromFileError};
+//! journalr keeps an encrypted diary with one entry per day.
+//!
+//! With the default `tui` feature this crate is also the terminal editor. Without it, it is
+//! the library other programs use to read and write diaries, mainly through [`Session`]:
+//!
+//! ```no_run
+//! use journalr::{Credentials, Date, Session};
+//!
+//! let mut diary = Session::open("diary.jrnl", Credentials::Password("password", None))?;
+//! diary.append(Date::today(), "Written by a script.");
+//! for (date, text) in diary.between("01-01-2024".parse()?, Date::today()) {
+//!     println!("{date}: {text}");
+//! }
+//! diary.save()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+#[cfg(feature = "tui")]
 use tui_textarea::TextArea;
 
+#[cfg(feature = "tui")]
 pub mod app;
+#[cfg(feature = "tui")]
 pub mod args;
+pub mod attempts;
+#[cfg(feature = "tui")]
+pub mod commands;
+pub mod crypto;
 pub mod date;
 pub mod diary;
+#[cfg(feature = "tui")]
+pub mod events;
+pub mod git;
+pub mod journal;
+pub mod lock;
+pub mod secret;
+pub mod session;
+#[cfg(feature = "tui")]
+pub mod signals;
+pub mod storage;
+#[cfg(all(test, feature = "tui"))]
+mod test_support;
+#[cfg(feature = "tui")]
 pub mod ui;
+pub mod watch;
+
+pub use crypto::{DiaryKey, Identity, Kdf, Keyfile};
+pub use date::Date;
+pub use diary::{Diary, DiaryFromFileError};
+pub use session::Session;
+pub use storage::{Credentials, Storage};
+
+#[cfg(feature = "tui")]
 pub fn clear(ta: &mut TextArea<'_>) {
     ta.move_cursor(tui_textarea::CursorMove::Jump(0, 0));
     ta.delete_str(ta.lines().iter().fold(0, |len, x| len + 1 + x.len()));
 }
-pub fn check_format(path: &str) -> bool {
-    let res = Diary::read_jrnl(path, "");
-    res.is_ok() || res.is_err_and(|e| !(e == DiaryFromFileError::WrongPassword))
-}
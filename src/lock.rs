@@ -0,0 +1,233 @@
+//! Advisory locking so only one journalr edits a diary at a time.
+//!
+//! The lock is a `<diary>.lock` file next to the diary, holding the PID and hostname of the
+//! process that owns it. It is written to a temporary file first and then linked into place,
+//! so a lock file that exists is complete.
+use std::{
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Who holds the lock on a diary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub hostname: String,
+    pub since: DateTime<Utc>,
+}
+impl LockHolder {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            since: Utc::now(),
+        }
+    }
+    /// True if the holder is known to have exited without releasing the lock.
+    fn is_stale(&self) -> bool {
+        self.hostname == hostname() && !process_alive(self.pid)
+    }
+}
+impl Display for LockHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "process {} on {} since {}",
+            self.pid,
+            self.hostname,
+            self.since.with_timezone(&Local).format("%d-%m-%Y %H:%M")
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another live (or possibly live) process has the diary open; `None` if the lock file
+    /// does not say which.
+    Held(Option<LockHolder>),
+    Io(io::Error),
+}
+impl From<io::Error> for LockError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Held(Some(holder)) => write!(f, "The diary is open in {holder}"),
+            Self::Held(None) => write!(f, "The diary is locked by an unreadable lock file"),
+            Self::Io(e) => write!(f, "Could not lock the diary: {e}"),
+        }
+    }
+}
+impl std::error::Error for LockError {}
+
+/// An acquired lock, released when dropped.
+#[derive(Debug)]
+pub struct DiaryLock {
+    path: PathBuf,
+}
+impl DiaryLock {
+    fn lock_path(diary: &str) -> PathBuf {
+        format!("{diary}.lock").into()
+    }
+    /// Lock the diary at `diary`, taking over locks left behind by crashed processes.
+    pub fn acquire(diary: &str) -> Result<Self, LockError> {
+        let path = Self::lock_path(diary);
+        match Self::create(&path) {
+            Err(LockError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+                match Self::holder(diary) {
+                    Some(holder) if holder.is_stale() => Self::take_over(diary, &holder),
+                    // Lock files are never seen half-written, so one that cannot be read was
+                    // not written by journalr and is left to the user to break.
+                    holder => Err(LockError::Held(holder)),
+                }
+            }
+            res => res,
+        }
+    }
+    /// Replace the lock `stale` left behind. Another process may have seen it too and taken
+    /// over first, so it is only removed if it still names `stale`, and whoever then creates
+    /// the lock first holds it.
+    fn take_over(diary: &str, stale: &LockHolder) -> Result<Self, LockError> {
+        let path = Self::lock_path(diary);
+        if Self::holder(diary).as_ref() == Some(stale) {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        match Self::create(&path) {
+            Err(LockError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(LockError::Held(Self::holder(diary)))
+            }
+            res => res,
+        }
+    }
+    /// Lock the diary even if someone else holds the lock.
+    pub fn force(diary: &str) -> Result<Self, LockError> {
+        let path = Self::lock_path(diary);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        Self::create(&path)
+    }
+    /// The current holder of the lock on `diary`, if any.
+    pub fn holder(diary: &str) -> Option<LockHolder> {
+        let contents = fs::read(Self::lock_path(diary)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+    fn create(path: &PathBuf) -> Result<Self, LockError> {
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!(".{}.tmp", std::process::id()));
+        let temp = PathBuf::from(temp);
+        let holder = serde_json::to_vec(&LockHolder::current()).map_err(io::Error::from)?;
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .and_then(|mut file| file.write_all(&holder))
+            // Unlike renaming, linking fails if the lock exists.
+            .and_then(|_| fs::hard_link(&temp, path));
+        let _ = fs::remove_file(&temp);
+        written?;
+        Ok(Self { path: path.clone() })
+    }
+}
+impl Drop for DiaryLock {
+    /// Release the lock, unless someone broke it and holds it now.
+    fn drop(&mut self) {
+        let Some(holder) = fs::read(&self.path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<LockHolder>(&contents).ok())
+        else {
+            return;
+        };
+        if holder.pid == std::process::id() && holder.hostname == hostname() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists; EPERM means it does but isn't ours.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // Without a way to check, assume the holder is alive and let the user break the lock.
+    true
+}
+
+#[test]
+fn lock_is_exclusive_and_released_on_drop() {
+    let diary = std::env::temp_dir().join(format!("journalr-lock-{}.jrnl", std::process::id()));
+    let diary = diary.to_str().unwrap();
+    let lock = DiaryLock::acquire(diary).unwrap();
+    match DiaryLock::acquire(diary) {
+        Err(LockError::Held(Some(holder))) => assert_eq!(holder.pid, std::process::id()),
+        res => panic!("expected the lock to be held, got {res:?}"),
+    }
+    drop(lock);
+    assert!(DiaryLock::holder(diary).is_none());
+    let stale = LockHolder {
+        pid: u32::MAX,
+        ..LockHolder::current()
+    };
+    fs::write(
+        DiaryLock::lock_path(diary),
+        serde_json::to_vec(&stale).unwrap(),
+    )
+    .unwrap();
+    let lock = DiaryLock::acquire(diary).unwrap();
+    assert_eq!(DiaryLock::holder(diary).unwrap().pid, std::process::id());
+    // Whoever saw the same stale lock later does not remove the new one.
+    assert!(matches!(
+        DiaryLock::take_over(diary, &stale),
+        Err(LockError::Held(Some(_)))
+    ));
+    assert_eq!(DiaryLock::holder(diary).unwrap().pid, std::process::id());
+    drop(lock);
+    // Garbage is not taken for a stale lock.
+    fs::write(DiaryLock::lock_path(diary), "").unwrap();
+    assert!(matches!(
+        DiaryLock::acquire(diary),
+        Err(LockError::Held(None))
+    ));
+    // A lock that was broken by someone else is theirs to release.
+    let lock = DiaryLock::force(diary).unwrap();
+    fs::write(
+        DiaryLock::lock_path(diary),
+        serde_json::to_vec(&stale).unwrap(),
+    )
+    .unwrap();
+    drop(lock);
+    assert_eq!(DiaryLock::holder(diary), Some(stale));
+    fs::remove_file(DiaryLock::lock_path(diary)).unwrap();
+}
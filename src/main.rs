@@ -2,7 +2,15 @@ use clap::Parser;
 use journalr::{app::App, args::Arguments};
 
 fn main() {
-    let app = App::try_from(Arguments::parse());
+    let mut args = Arguments::parse();
+    if let Some(command) = args.command.take() {
+        if let Err(e) = command.run() {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    let app = App::try_from(args);
     match app {
         Ok(app) => app.run().unwrap_or_else(|e| println!("{e}")),
         Err(e) => eprintln!("{e}"),
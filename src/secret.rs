@@ -0,0 +1,196 @@
+//! Handling of passwords and decrypted diary text in memory.
+//!
+//! Everything secret is kept in [`Zeroizing`] containers so it is overwritten when dropped.
+use std::ops::Deref;
+
+#[cfg(feature = "tui")]
+use tui_textarea::TextArea;
+pub use zeroize::{Zeroize, Zeroizing};
+
+/// The diary password.
+///
+/// Wiped on drop and, when created with `mlock`, kept out of swap for as long as it lives.
+#[derive(Default)]
+pub struct Password {
+    inner: Zeroizing<String>,
+    locked: bool,
+}
+impl Password {
+    pub fn new(password: impl Into<String>, mlock: bool) -> Self {
+        let inner = Zeroizing::new(password.into());
+        let locked = mlock && lock_memory(inner.as_bytes());
+        Self { inner, locked }
+    }
+}
+impl Deref for Password {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+impl Drop for Password {
+    fn drop(&mut self) {
+        if self.locked {
+            let bytes: *const [u8] = self.inner.as_bytes();
+            self.inner.zeroize();
+            // Zeroizing a `String` keeps its allocation, so `bytes` is still valid here.
+            unlock_memory(unsafe { &*bytes });
+        }
+    }
+}
+
+/// How hard a password is to guess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strength {
+    Empty,
+    VeryWeak,
+    Weak,
+    Fair,
+    Strong,
+    VeryStrong,
+}
+impl Strength {
+    pub fn of(bits: f64) -> Self {
+        match bits {
+            b if b <= 0.0 => Self::Empty,
+            b if b < 28.0 => Self::VeryWeak,
+            b if b < 36.0 => Self::Weak,
+            b if b < 60.0 => Self::Fair,
+            b if b < 128.0 => Self::Strong,
+            _ => Self::VeryStrong,
+        }
+    }
+}
+impl std::fmt::Display for Strength {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Empty => "Empty",
+            Self::VeryWeak => "Very weak",
+            Self::Weak => "Weak",
+            Self::Fair => "Fair",
+            Self::Strong => "Strong",
+            Self::VeryStrong => "Very strong",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Rough entropy of `password` in bits, from the character classes it uses and its length.
+///
+/// Characters repeating or continuing a run of the previous one (`aaa`, `abc`, `321`) count for
+/// little, since they are the first things a guesser tries.
+pub fn entropy_bits(password: &str) -> f64 {
+    let mut pool = 0;
+    for (present, size) in [
+        (password.chars().any(|c| c.is_ascii_lowercase()), 26),
+        (password.chars().any(|c| c.is_ascii_uppercase()), 26),
+        (password.chars().any(|c| c.is_ascii_digit()), 10),
+        (
+            password
+                .chars()
+                .any(|c| c.is_ascii_punctuation() || c == ' '),
+            33,
+        ),
+        (!password.is_ascii(), 100),
+    ] {
+        if present {
+            pool += size;
+        }
+    }
+    let mut length = 0.0;
+    let mut previous: Option<char> = None;
+    for c in password.chars() {
+        let step = previous.map(|p| c as i64 - p as i64);
+        length += if matches!(step, Some(-1..=1)) {
+            0.25
+        } else {
+            1.0
+        };
+        previous = Some(c);
+    }
+    length * f64::from(pool).max(1.0).log2()
+}
+
+/// The least a new password has to satisfy. An empty password is always allowed after asking,
+/// as the choice to not encrypt the diary.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Minimum [`entropy_bits`].
+    pub min_bits: u32,
+}
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_bits: 36,
+        }
+    }
+}
+impl PasswordPolicy {
+    /// Why `password` is not allowed, if it isn't.
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            Err(format!("Use at least {} characters", self.min_length))
+        } else if entropy_bits(password) < f64::from(self.min_bits) {
+            Err("Too easy to guess; make it longer or mix in other kinds of characters".into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Pin the pages holding `bytes` in RAM. Returns false if the OS refused.
+#[cfg(unix)]
+pub fn lock_memory(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && unsafe { libc::mlock(bytes.as_ptr().cast(), bytes.len()) == 0 }
+}
+#[cfg(not(unix))]
+pub fn lock_memory(_bytes: &[u8]) -> bool {
+    false
+}
+#[cfg(unix)]
+pub fn unlock_memory(bytes: &[u8]) {
+    if !bytes.is_empty() {
+        unsafe { libc::munlock(bytes.as_ptr().cast(), bytes.len()) };
+    }
+}
+#[cfg(not(unix))]
+pub fn unlock_memory(_bytes: &[u8]) {}
+
+/// Stop the process from writing core dumps, which would contain the decrypted diary.
+#[cfg(unix)]
+pub fn disable_core_dumps() {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+#[cfg(not(unix))]
+pub fn disable_core_dumps() {}
+
+/// Overwrite the text held by `ta`.
+///
+/// This does not reach the undo history, so text areas meant for secrets should be created
+/// with `set_max_histories(0)`.
+#[cfg(feature = "tui")]
+pub fn wipe(ta: TextArea<'_>) {
+    ta.into_lines().zeroize();
+}
+
+#[test]
+fn entropy_penalises_runs_and_policy_applies() {
+    assert_eq!(entropy_bits(""), 0.0);
+    assert!(entropy_bits("abcdefgh") < entropy_bits("hqzmxwra"));
+    assert!(Strength::of(entropy_bits("aaaaaaaaaaaa")) <= Strength::Weak);
+    assert!(Strength::of(entropy_bits("correct horse battery staple")) >= Strength::Strong);
+    let policy = PasswordPolicy::default();
+    assert!(policy.check("Tr0ub4dor&3").is_ok());
+    assert!(policy.check("short").is_err());
+    assert!(policy.check("12345678").is_err());
+}
@@ -0,0 +1,188 @@
+//! Reading and writing diaries from other programs, without the editor.
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    crypto::DiaryKey,
+    date::Date,
+    diary::{Diary, DiaryFromFileError, TrashedEntry},
+    storage::{self, Credentials, Storage},
+    watch::FileStamp,
+};
+
+/// An unlocked diary and where it is kept.
+///
+/// Changes stay in memory until [`Session::save`], which records earlier versions of changed
+/// entries the same way the editor does. The editor's write lock is not taken, so a diary that
+/// is open in the editor meanwhile is reported there as changed on disk; likewise `save` refuses
+/// to overwrite what the editor or anything else wrote since the session loaded or saved.
+pub struct Session {
+    storage: Box<dyn Storage>,
+    /// The stored diary as of the last load or save, if there was one.
+    stamp: Option<FileStamp>,
+    diary: Diary,
+    key: DiaryKey,
+    /// Text of each entry as of the last load or save.
+    saved: HashMap<Date, String>,
+    /// Number of earlier versions kept for each entry.
+    pub max_revisions: usize,
+}
+impl Session {
+    /// Unlock the diary at `path`, which is a file, a directory or a database as for `--file`.
+    pub fn open(path: &str, credentials: Credentials<'_>) -> Result<Self, DiaryFromFileError> {
+        Self::load(storage::open(path), credentials)
+    }
+    /// Unlock the diary kept in `storage`.
+    pub fn load(
+        mut storage: Box<dyn Storage>,
+        credentials: Credentials<'_>,
+    ) -> Result<Self, DiaryFromFileError> {
+        let (diary, key) = storage.load(credentials)?;
+        Ok(Self {
+            stamp: storage.metadata().ok(),
+            storage,
+            saved: diary.entries.clone(),
+            diary,
+            key,
+            max_revisions: Diary::DEFAULT_MAX_REVISIONS,
+        })
+    }
+    /// Start an empty diary at `path`, written right away with `key`, e.g. from
+    /// [`DiaryKey::new`].
+    pub fn create(path: &str, key: DiaryKey) -> Result<Self, DiaryFromFileError> {
+        if Path::new(path).exists() {
+            return Err(DiaryFromFileError::AlreadyExists);
+        }
+        let mut storage = storage::open(path);
+        let diary = Diary::new();
+        storage.create()?;
+        storage.save(&diary, &key)?;
+        Ok(Self {
+            stamp: storage.metadata().ok(),
+            storage,
+            diary,
+            key,
+            saved: HashMap::new(),
+            max_revisions: Diary::DEFAULT_MAX_REVISIONS,
+        })
+    }
+    /// All entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = (Date, &str)> {
+        let mut entries: Vec<_> = self
+            .diary
+            .entries
+            .iter()
+            .map(|(date, text)| (*date, text.as_str()))
+            .collect();
+        entries.sort_unstable_by_key(|(date, _)| *date);
+        entries.into_iter()
+    }
+    /// The entries from `from` to `to` inclusive, oldest first.
+    pub fn between(&self, from: Date, to: Date) -> impl Iterator<Item = (Date, &str)> {
+        self.entries()
+            .filter(move |(date, _)| (from..=to).contains(date))
+    }
+    pub fn get(&self, date: Date) -> Option<&str> {
+        self.diary.entries.get(&date).map(String::as_str)
+    }
+    /// Replace the entry for `date`, returning what it said before.
+    pub fn put(&mut self, date: Date, text: impl Into<String>) -> Option<String> {
+        self.diary.entries.insert(date, text.into())
+    }
+    /// Add `text` as a new paragraph at the end of the entry for `date`, starting the entry if
+    /// there is none.
+    pub fn append(&mut self, date: Date, text: &str) {
+        let entry = self.diary.entries.entry(date).or_default();
+        if !entry.trim().is_empty() {
+            entry.push_str("\n\n");
+        }
+        entry.push_str(text);
+    }
+    /// Move the entry for `date` to the trash, where the editor can restore it from. Returns
+    /// false if there was no such entry.
+    pub fn remove(&mut self, date: Date) -> bool {
+        match self.diary.entries.remove(&date) {
+            Some(text) => {
+                if !text.trim().is_empty() {
+                    self.diary.trash.push(TrashedEntry::new(date, text));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+    /// Write the diary back to where it was opened or created, unless it was changed there
+    /// since; then [`DiaryFromFileError::ChangedOnDisk`] is returned and nothing is written.
+    pub fn save(&mut self) -> Result<(), DiaryFromFileError> {
+        if let Some(stamp) = &mut self.stamp {
+            if self.storage.changed(stamp)?.is_some() {
+                return Err(DiaryFromFileError::ChangedOnDisk);
+            }
+        }
+        self.diary.record_revisions(&self.saved, self.max_revisions);
+        self.storage.save(&self.diary, &self.key)?;
+        self.stamp = self.storage.metadata().ok();
+        self.saved.clone_from(&self.diary.entries);
+        Ok(())
+    }
+    /// The whole diary, including trash and history.
+    pub fn diary(&self) -> &Diary {
+        &self.diary
+    }
+    pub fn key(&self) -> &DiaryKey {
+        &self.key
+    }
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.saved
+            .values_mut()
+            .for_each(crate::secret::Zeroize::zeroize);
+    }
+}
+
+#[test]
+fn edits_are_saved_and_listed_in_order() {
+    use crate::storage::MemoryStorage;
+    let date = |s: &str| s.parse::<Date>().unwrap();
+    let storage = MemoryStorage::new();
+    let mut diary = Diary::new();
+    diary.entries.insert(date("02-03-2024"), "march".to_owned());
+    storage.clone().save(&diary, &DiaryKey::Plaintext).unwrap();
+    let mut session =
+        Session::load(Box::new(storage.clone()), Credentials::Password("", None)).unwrap();
+    session.put(date("01-01-2024"), "january");
+    session.append(date("01-01-2024"), "more");
+    session.append(date("05-05-2024"), "may");
+    assert!(session.remove(date("02-03-2024")));
+    session.save().unwrap();
+    let mut session =
+        Session::load(Box::new(storage.clone()), Credentials::Password("", None)).unwrap();
+    assert_eq!(
+        session.entries().collect::<Vec<_>>(),
+        vec![
+            (date("01-01-2024"), "january\n\nmore"),
+            (date("05-05-2024"), "may")
+        ]
+    );
+    assert_eq!(
+        session
+            .between(date("01-02-2024"), date("31-12-2024"))
+            .count(),
+        1
+    );
+    assert_eq!(session.diary().trash[0].text, "march");
+    // What something else saved meanwhile is not overwritten.
+    storage.clone().save(&diary, &DiaryKey::Plaintext).unwrap();
+    session.put(date("06-06-2024"), "june");
+    assert_eq!(session.save(), Err(DiaryFromFileError::ChangedOnDisk));
+}
+#[test]
+fn created_diaries_open_before_the_first_save() {
+    let path = std::env::temp_dir().join(format!("journalr-session-{}.jrnl", std::process::id()));
+    let path = path.to_string_lossy();
+    let mut session = Session::create(&path, DiaryKey::Plaintext).unwrap();
+    assert!(Session::open(&path, Credentials::Nothing).is_ok());
+    std::fs::write(&*path, "{\"entries\":{}}\n").unwrap();
+    assert_eq!(session.save(), Err(DiaryFromFileError::ChangedOnDisk));
+    std::fs::remove_file(&*path).unwrap();
+}
@@ -0,0 +1,79 @@
+//! Leaving the terminal usable, and unsaved text recoverable, when journalr dies unexpectedly.
+use std::{
+    io::stderr,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+};
+
+use crossterm::{
+    terminal::{disable_raw_mode, LeaveAlternateScreen},
+    ExecutableCommand,
+};
+
+/// The last termination signal received, or 0.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+/// Set by a SIGTSTP from outside, e.g. `kill -TSTP`. In raw mode Ctrl+Z arrives as a key instead.
+static SUSPEND: AtomicBool = AtomicBool::new(false);
+
+/// Restore the terminal before panic messages are printed, and note termination signals so the
+/// editor can save what it can and quit.
+pub fn install() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        previous(info);
+    }));
+    #[cfg(unix)]
+    for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGINT] {
+        // Only stores to an atomic, which is async-signal-safe.
+        unsafe {
+            libc::signal(
+                signal,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(
+            libc::SIGTSTP,
+            on_suspend as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        )
+    };
+}
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+}
+#[cfg(unix)]
+extern "C" fn on_suspend(_: libc::c_int) {
+    SUSPEND.store(true, Ordering::SeqCst);
+}
+/// Whether a suspend was requested since this was last called.
+pub fn take_suspend() -> bool {
+    SUSPEND.swap(false, Ordering::SeqCst)
+}
+/// Stop the process the way job control expects, returning once it is continued.
+#[cfg(unix)]
+pub fn stop() {
+    unsafe {
+        libc::signal(libc::SIGTSTP, libc::SIG_DFL);
+        libc::raise(libc::SIGTSTP);
+        libc::signal(
+            libc::SIGTSTP,
+            on_suspend as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+#[cfg(not(unix))]
+pub fn stop() {}
+/// The termination signal received since [`install`], if any.
+pub fn received() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+pub fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = stderr().execute(LeaveAlternateScreen);
+}
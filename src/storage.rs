@@ -0,0 +1,456 @@
+//! Where diaries are kept.
+//!
+//! [`Storage`] hides how a diary is laid out so the editor does not care whether it is one file,
+//! a directory with a file per day, or a buffer in memory.
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::{DiaryKey, Identity, Keyfile, RecoveryKey},
+    date::Date,
+    diary::{Diary, DiaryFromFileError, Revision, TrashedEntry},
+    journal::Journal,
+    lock::{DiaryLock, LockError},
+    secret::Zeroizing,
+    watch::FileStamp,
+};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// What a diary is unlocked with.
+#[derive(Clone, Copy)]
+pub enum Credentials<'a> {
+    /// Nothing, which only opens diaries that need no password.
+    Nothing,
+    Password(&'a str, Option<&'a Keyfile>),
+    Identity(&'a Identity),
+    Recovery(&'a RecoveryKey),
+}
+
+pub trait Storage {
+    /// Shown to the user, and the key unlock attempts are recorded under.
+    fn name(&self) -> &str;
+    /// Make room for a new, empty diary.
+    fn create(&mut self) -> io::Result<()>;
+    fn load(
+        &mut self,
+        credentials: Credentials<'_>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError>;
+    fn save(&mut self, diary: &Diary, key: &DiaryKey) -> Result<(), DiaryFromFileError>;
+    /// Keep other instances from editing the diary. `None` if the storage cannot be shared.
+    fn lock(&self) -> Result<Option<DiaryLock>, LockError>;
+    /// Like [`lock`](Self::lock), but taking the lock from whoever holds it.
+    fn force_lock(&self) -> Result<Option<DiaryLock>, LockError>;
+    /// What the stored diary looks like now.
+    fn metadata(&self) -> io::Result<FileStamp>;
+    /// Where unsaved edits are journaled, if anywhere.
+    fn journal(&self) -> Option<Journal> {
+        Some(Journal::next_to(self.name()))
+    }
+    /// The current metadata if the diary changed since `since` was taken. `since` may be
+    /// refreshed with metadata that changed while the diary did not.
+    fn changed(&self, since: &mut FileStamp) -> io::Result<Option<FileStamp>> {
+        let now = self.metadata()?;
+        Ok((now != *since).then_some(now))
+    }
+}
+
+/// The storage for `path`: a [`DirectoryStorage`] if it is a directory or ends with a
+/// separator, a `SqliteStorage` if it is a database (or is new with a database extension) and
+/// the `sqlite` feature is on, else a [`FileStorage`].
+pub fn open(path: &str) -> Box<dyn Storage> {
+    #[cfg(feature = "sqlite")]
+    if SqliteStorage::is_database(path) {
+        return Box::new(SqliteStorage::new(path));
+    }
+    if Path::new(path).is_dir() || path.ends_with(['/', MAIN_SEPARATOR]) {
+        Box::new(DirectoryStorage::new(path))
+    } else {
+        Box::new(FileStorage::new(path))
+    }
+}
+
+/// The whole diary in a single file.
+pub struct FileStorage {
+    path: String,
+}
+impl FileStorage {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+}
+impl Storage for FileStorage {
+    fn name(&self) -> &str {
+        &self.path
+    }
+    fn create(&mut self) -> io::Result<()> {
+        File::create(&self.path).map(drop)
+    }
+    fn load(
+        &mut self,
+        credentials: Credentials<'_>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+        open_diary(&mut File::open(&self.path)?, credentials)
+    }
+    fn save(&mut self, diary: &Diary, key: &DiaryKey) -> Result<(), DiaryFromFileError> {
+        diary.write_with(&self.path, key)
+    }
+    fn lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::acquire(&self.path).map(Some)
+    }
+    fn force_lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::force(&self.path).map(Some)
+    }
+    fn metadata(&self) -> io::Result<FileStamp> {
+        FileStamp::of(&self.path)
+    }
+    fn changed(&self, since: &mut FileStamp) -> io::Result<Option<FileStamp>> {
+        since.changed(&self.path)
+    }
+}
+
+/// A diary kept in memory, sealed as it would be in a file. Clones share the same diary.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    bytes: Arc<Mutex<Option<Vec<u8>>>>,
+}
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The diary as it would be written to a file.
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        self.bytes.lock().unwrap().clone()
+    }
+}
+impl Storage for MemoryStorage {
+    fn name(&self) -> &str {
+        "<memory>"
+    }
+    fn create(&mut self) -> io::Result<()> {
+        *self.bytes.lock().unwrap() = Some(Vec::new());
+        Ok(())
+    }
+    fn load(
+        &mut self,
+        credentials: Credentials<'_>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+        let bytes = self.bytes.lock().unwrap();
+        let mut reader = bytes.as_deref().ok_or(DiaryFromFileError::NotFound)?;
+        open_diary(&mut reader, credentials)
+    }
+    fn save(&mut self, diary: &Diary, key: &DiaryKey) -> Result<(), DiaryFromFileError> {
+        let mut bytes = Vec::new();
+        diary.write_into(&mut bytes, key)?;
+        *self.bytes.lock().unwrap() = Some(bytes);
+        Ok(())
+    }
+    fn lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        Ok(None)
+    }
+    fn force_lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        Ok(None)
+    }
+    fn journal(&self) -> Option<Journal> {
+        None
+    }
+    fn metadata(&self) -> io::Result<FileStamp> {
+        let bytes = self.bytes.lock().unwrap();
+        let bytes = bytes.as_deref().ok_or(io::ErrorKind::NotFound)?;
+        Ok(FileStamp::of_contents(bytes))
+    }
+}
+
+/// A directory holding each day of the diary in its own file, so saving only rewrites the days
+/// that changed.
+///
+/// `diary.jrnl` holds the key and the trash; every other file is named after its date as
+/// `YYYY-MM-DD.jrnl` and holds that day's entry and earlier versions of it. All of them are sealed
+/// with the same content key.
+pub struct DirectoryStorage {
+    dir: String,
+    days: Days,
+}
+/// How backends that store each day separately name it, sorting by date.
+fn day_name(date: Date) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+fn parse_day_name(name: &str) -> Option<Date> {
+    NaiveDate::parse_from_str(name, "%Y-%m-%d")
+        .ok()
+        .map(Date::from)
+}
+/// Unlock the key of a diary and decrypt what `reader` holds with it.
+fn open_key(
+    reader: &mut impl Read,
+    credentials: Credentials<'_>,
+) -> Result<(Zeroizing<Vec<u8>>, DiaryKey), DiaryFromFileError> {
+    match credentials {
+        Credentials::Nothing => DiaryKey::open_unprotected(reader),
+        Credentials::Password(password, keyfile) => DiaryKey::open(reader, password, keyfile),
+        Credentials::Identity(identity) => DiaryKey::open_as(reader, identity),
+        Credentials::Recovery(recovery) => DiaryKey::recover(reader, recovery),
+    }
+}
+/// Unlock a diary kept in a single file.
+pub(crate) fn open_diary(
+    reader: &mut impl Read,
+    credentials: Credentials<'_>,
+) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+    let (bytes, key) = open_key(reader, credentials)?;
+    Ok((serde_json::from_slice(&bytes)?, key))
+}
+/// What backends that store each day separately keep besides the days.
+#[derive(Serialize, Deserialize)]
+struct Index<'a> {
+    #[serde(default)]
+    trash: Cow<'a, [TrashedEntry]>,
+}
+impl Index<'_> {
+    /// The index of `diary`, serialized for `key`.
+    fn of(diary: &Diary, key: &DiaryKey) -> serde_json::Result<Vec<u8>> {
+        let index = Index {
+            trash: Cow::Borrowed(&diary.trash),
+        };
+        key.serialize(&index)
+    }
+    /// A diary with the days still to be added.
+    fn read(bytes: &[u8]) -> Result<Diary, DiaryFromFileError> {
+        let index: Index = serde_json::from_slice(bytes)?;
+        let mut diary = Diary::new();
+        diary.trash = index.trash.into_owned();
+        Ok(diary)
+    }
+}
+/// What is stored for each day.
+#[derive(Serialize, Deserialize)]
+struct Day<'a> {
+    /// Sealed with the text, so a day moved under another name is not read as that date.
+    date: Date,
+    /// `None` if the entry was deleted but its history is kept.
+    text: Option<Cow<'a, str>>,
+    #[serde(default)]
+    history: Cow<'a, [Revision]>,
+}
+/// Digest of each day as last loaded or saved, to tell which ones need writing.
+///
+/// The digests cover the key each day was sealed with, so every day is written again when the
+/// content key changes.
+#[derive(Clone, Default)]
+struct Days(HashMap<Date, u64>);
+impl Days {
+    fn digest(key: &DiaryKey, json: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.fingerprint().hash(&mut hasher);
+        json.hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Add the stored day `date`, sealed with `key`, to `diary`.
+    fn read(
+        &mut self,
+        diary: &mut Diary,
+        date: Date,
+        key: &DiaryKey,
+        bytes: &[u8],
+    ) -> Result<(), DiaryFromFileError> {
+        let day: Day = serde_json::from_slice(bytes)?;
+        if day.date != date {
+            return Err(DiaryFromFileError::InvalidFormat);
+        }
+        if let Some(text) = day.text {
+            diary.entries.insert(date, text.into_owned());
+        }
+        if !day.history.is_empty() {
+            diary.history.insert(date, day.history.into_owned());
+        }
+        self.0.insert(date, Self::digest(key, bytes));
+        Ok(())
+    }
+    /// Pass each day of `diary` that changed since it was loaded or saved to `write`, and each
+    /// day that is gone to `remove`.
+    fn write(
+        &mut self,
+        diary: &Diary,
+        key: &DiaryKey,
+        mut write: impl FnMut(Date, Vec<u8>) -> Result<(), DiaryFromFileError>,
+        mut remove: impl FnMut(Date) -> Result<(), DiaryFromFileError>,
+    ) -> Result<(), DiaryFromFileError> {
+        // Legacy keys would run the slow password hash for every day.
+        if key.is_legacy() {
+            return Err(DiaryFromFileError::LegacyFormat);
+        }
+        let mut written = HashMap::new();
+        for &date in diary.entries.keys().chain(diary.history.keys()) {
+            if written.contains_key(&date) {
+                continue;
+            }
+            let day = Day {
+                date,
+                text: diary
+                    .entries
+                    .get(&date)
+                    .map(|text| Cow::Borrowed(text.as_str())),
+                history: Cow::Borrowed(diary.history.get(&date).map_or(&[], Vec::as_slice)),
+            };
+            let mut json = Zeroizing::new(key.serialize(&day)?);
+            let digest = Self::digest(key, &json);
+            if self.0.get(&date) != Some(&digest) {
+                write(date, std::mem::take(&mut *json))?;
+            }
+            written.insert(date, digest);
+        }
+        for &date in self.0.keys().filter(|d| !written.contains_key(d)) {
+            remove(date)?;
+        }
+        self.0 = written;
+        Ok(())
+    }
+}
+impl DirectoryStorage {
+    const INDEX: &'static str = "diary.jrnl";
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.trim_end_matches(['/', MAIN_SEPARATOR]).to_owned(),
+            days: Days::default(),
+        }
+    }
+    fn index(&self) -> PathBuf {
+        Path::new(&self.dir).join(Self::INDEX)
+    }
+    fn day(&self, date: Date) -> PathBuf {
+        Path::new(&self.dir).join(format!("{}.jrnl", day_name(date)))
+    }
+    fn date_of(path: &Path) -> Option<Date> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".jrnl")?;
+        parse_day_name(name)
+    }
+}
+impl Storage for DirectoryStorage {
+    fn name(&self) -> &str {
+        &self.dir
+    }
+    fn create(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)
+    }
+    fn load(
+        &mut self,
+        credentials: Credentials<'_>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+        let (bytes, key) = open_key(&mut File::open(self.index())?, credentials)?;
+        let mut diary = Index::read(&bytes)?;
+        let mut days = Days::default();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(date) = Self::date_of(&path) else {
+                continue;
+            };
+            days.read(
+                &mut diary,
+                date,
+                &key,
+                &key.unseal(&mut File::open(&path)?)?,
+            )?;
+        }
+        self.days = days;
+        Ok((diary, key))
+    }
+    fn save(&mut self, diary: &Diary, key: &DiaryKey) -> Result<(), DiaryFromFileError> {
+        fs::create_dir_all(&self.dir)?;
+        let mut days = std::mem::take(&mut self.days);
+        let res = days.write(
+            diary,
+            key,
+            |date, json| key.seal(json, &mut File::create(self.day(date))?),
+            |date| match fs::remove_file(self.day(date)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        );
+        self.days = days;
+        res?;
+        // Written last, so its metadata changes with every save.
+        key.seal(Index::of(diary, key)?, &mut File::create(self.index())?)
+    }
+    fn lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::acquire(&self.dir).map(Some)
+    }
+    fn force_lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::force(&self.dir).map(Some)
+    }
+    fn metadata(&self) -> io::Result<FileStamp> {
+        FileStamp::of(&self.index().to_string_lossy())
+    }
+    fn changed(&self, since: &mut FileStamp) -> io::Result<Option<FileStamp>> {
+        since.changed(&self.index().to_string_lossy())
+    }
+}
+
+#[test]
+fn directory_storage_only_rewrites_changed_days() {
+    use crate::crypto::Kdf;
+    let dir = std::env::temp_dir().join(format!("journalr-storage-{}", std::process::id()));
+    let mut storage = open(&format!("{}/", dir.display()));
+    let mut key = DiaryKey::new("pw", Kdf::argon2id(64, 1, 1).unwrap(), None).unwrap();
+    let (first, second) = ("01-02-2024".parse().unwrap(), "02-02-2024".parse().unwrap());
+    let mut diary = Diary::new();
+    diary.entries.insert(first, "one".to_owned());
+    diary.entries.insert(second, "two".to_owned());
+    storage.save(&diary, &key).unwrap();
+    let untouched = fs::metadata(dir.join("2024-02-01.jrnl"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    diary.entries.remove(&second);
+    storage.save(&diary, &key).unwrap();
+    assert!(!dir.join("2024-02-02.jrnl").exists());
+    let modified = fs::metadata(dir.join("2024-02-01.jrnl"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(untouched, modified);
+    let (loaded, _) = storage.load(Credentials::Password("pw", None)).unwrap();
+    assert_eq!(loaded.entries, diary.entries);
+    // Days sealed with the old content key would no longer open.
+    key.rotate("pw", None).unwrap();
+    storage.save(&diary, &key).unwrap();
+    let (loaded, _) = open(&dir.to_string_lossy())
+        .load(Credentials::Password("pw", None))
+        .unwrap();
+    assert_eq!(loaded.entries, diary.entries);
+    // A day copied under another date's name is refused.
+    fs::copy(dir.join("2024-02-01.jrnl"), dir.join("2024-02-03.jrnl")).unwrap();
+    assert!(matches!(
+        storage.load(Credentials::Password("pw", None)),
+        Err(DiaryFromFileError::InvalidFormat)
+    ));
+    fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn memory_storage_round_trips_sealed_diary() {
+    let mut storage = MemoryStorage::new();
+    assert_eq!(
+        storage.load(Credentials::Password("", None)).err(),
+        Some(DiaryFromFileError::NotFound)
+    );
+    let mut diary = Diary::new();
+    diary.entries.insert(Date::today(), "text".to_owned());
+    storage.save(&diary, &DiaryKey::Plaintext).unwrap();
+    let mut stamp = storage.metadata().unwrap();
+    let (loaded, key) = storage.load(Credentials::Password("", None)).unwrap();
+    assert!(key.is_plaintext());
+    assert_eq!(loaded.entries, diary.entries);
+    assert_eq!(storage.changed(&mut stamp).unwrap(), None);
+}
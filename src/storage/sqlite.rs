@@ -0,0 +1,222 @@
+//! Diaries in a SQLite database, with each day in its own encrypted row.
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use super::{day_name, open_key, parse_day_name, Credentials, Days, Index, Storage};
+use crate::{
+    crypto::DiaryKey,
+    date::Date,
+    diary::{Diary, DiaryFromFileError},
+    lock::{DiaryLock, LockError},
+    watch::FileStamp,
+};
+
+/// How every SQLite database file starts.
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS diary (id INTEGER PRIMARY KEY CHECK (id = 0), data BLOB NOT NULL);
+    CREATE TABLE IF NOT EXISTS days (date TEXT PRIMARY KEY, data BLOB NOT NULL) WITHOUT ROWID;
+";
+
+/// A diary in a SQLite database.
+///
+/// The single row of `diary` holds the key and the trash. `days` holds each day sealed on its
+/// own under the same content key, keyed by its date as `YYYY-MM-DD` so ranges of dates can be
+/// read without the rest of the diary.
+pub struct SqliteStorage {
+    path: String,
+    days: Days,
+}
+impl SqliteStorage {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            days: Days::default(),
+        }
+    }
+    /// Whether `path` names a database rather than a diary file: by its header if it exists,
+    /// else by its extension.
+    pub fn is_database(path: &str) -> bool {
+        let mut header = [0; 16];
+        match File::open(path) {
+            Ok(mut file) => file.read_exact(&mut header).is_ok() && header == *MAGIC,
+            Err(_) => Path::new(path)
+                .extension()
+                .is_some_and(|ext| ext == "sqlite" || ext == "sqlite3" || ext == "db"),
+        }
+    }
+    fn connect(&self, create: bool) -> Result<Connection, DiaryFromFileError> {
+        if !create && !Path::new(&self.path).exists() {
+            return Err(DiaryFromFileError::NotFound);
+        }
+        let conn = Connection::open(&self.path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+    /// The entries from `from` to `to` inclusive, oldest first.
+    pub fn entries_between(
+        &self,
+        key: &DiaryKey,
+        from: Date,
+        to: Date,
+    ) -> Result<Vec<(Date, String)>, DiaryFromFileError> {
+        let conn = self.connect(false)?;
+        let mut stmt =
+            conn.prepare("SELECT date, data FROM days WHERE date BETWEEN ?1 AND ?2 ORDER BY date")?;
+        let mut rows = stmt.query(params![day_name(from), day_name(to)])?;
+        let mut diary = Diary::new();
+        let mut order = Vec::new();
+        while let Some(row) = rows.next()? {
+            let date = parse_day_name(&row.get::<_, String>(0)?)
+                .ok_or(DiaryFromFileError::InvalidFormat)?;
+            let data = row.get::<_, Vec<u8>>(1)?;
+            Days::default().read(&mut diary, date, key, &key.unseal(&mut data.as_slice())?)?;
+            order.push(date);
+        }
+        Ok(order
+            .into_iter()
+            .filter_map(|date| Some((date, diary.entries.remove(&date)?)))
+            .collect())
+    }
+}
+impl Storage for SqliteStorage {
+    fn name(&self) -> &str {
+        &self.path
+    }
+    fn create(&mut self) -> io::Result<()> {
+        self.connect(true)
+            .map(drop)
+            .map_err(|e| io::Error::other(e.to_string()))
+    }
+    fn load(
+        &mut self,
+        credentials: Credentials<'_>,
+    ) -> Result<(Diary, DiaryKey), DiaryFromFileError> {
+        let conn = self.connect(false)?;
+        let index: Vec<u8> = conn
+            .query_row("SELECT data FROM diary", [], |row| row.get(0))
+            .optional()?
+            .ok_or(DiaryFromFileError::InvalidFormat)?;
+        let (bytes, key) = open_key(&mut index.as_slice(), credentials)?;
+        let mut diary = Index::read(&bytes)?;
+        let mut days = Days::default();
+        let mut stmt = conn.prepare("SELECT date, data FROM days")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let date = parse_day_name(&row.get::<_, String>(0)?)
+                .ok_or(DiaryFromFileError::InvalidFormat)?;
+            let data = row.get::<_, Vec<u8>>(1)?;
+            days.read(&mut diary, date, &key, &key.unseal(&mut data.as_slice())?)?;
+        }
+        self.days = days;
+        Ok((diary, key))
+    }
+    fn save(&mut self, diary: &Diary, key: &DiaryKey) -> Result<(), DiaryFromFileError> {
+        let mut conn = self.connect(true)?;
+        let tx = conn.transaction()?;
+        // Only kept if the transaction commits.
+        let mut days = self.days.clone();
+        days.write(
+            diary,
+            key,
+            |date, json| {
+                let mut data = Vec::new();
+                key.seal(json, &mut data)?;
+                tx.execute(
+                    "INSERT OR REPLACE INTO days (date, data) VALUES (?1, ?2)",
+                    params![day_name(date), data],
+                )?;
+                Ok(())
+            },
+            |date| {
+                tx.execute("DELETE FROM days WHERE date = ?1", [day_name(date)])?;
+                Ok(())
+            },
+        )?;
+        let mut index = Vec::new();
+        key.seal(Index::of(diary, key)?, &mut index)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO diary (id, data) VALUES (0, ?1)",
+            [index],
+        )?;
+        tx.commit()?;
+        self.days = days;
+        Ok(())
+    }
+    fn lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::acquire(&self.path).map(Some)
+    }
+    fn force_lock(&self) -> Result<Option<DiaryLock>, LockError> {
+        DiaryLock::force(&self.path).map(Some)
+    }
+    fn metadata(&self) -> io::Result<FileStamp> {
+        FileStamp::of(&self.path)
+    }
+    fn changed(&self, since: &mut FileStamp) -> io::Result<Option<FileStamp>> {
+        since.changed(&self.path)
+    }
+}
+
+impl From<rusqlite::Error> for DiaryFromFileError {
+    fn from(value: rusqlite::Error) -> Self {
+        match value.sqlite_error_code() {
+            Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt) => Self::InvalidFormat,
+            Some(_) => Self::NotAccessible,
+            // Rows that are missing or of the wrong type.
+            None => Self::InvalidFormat,
+        }
+    }
+}
+
+#[test]
+fn saves_rows_incrementally_and_reads_ranges() {
+    use crate::crypto::Kdf;
+    let path = std::env::temp_dir().join(format!("journalr-{}.sqlite", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut storage = SqliteStorage::new(path);
+    let key = DiaryKey::new("pw", Kdf::argon2id(64, 1, 1).unwrap(), None).unwrap();
+    let dates: Vec<Date> = ["01-02-2024", "02-02-2024", "03-02-2024"]
+        .iter()
+        .map(|d| d.parse().unwrap())
+        .collect();
+    let mut diary = Diary::new();
+    for (i, date) in dates.iter().enumerate() {
+        diary.entries.insert(*date, format!("day {i}"));
+    }
+    storage.save(&diary, &key).unwrap();
+    diary.entries.remove(&dates[2]);
+    storage.save(&diary, &key).unwrap();
+    let range = storage.entries_between(&key, dates[1], dates[2]).unwrap();
+    assert_eq!(range, vec![(dates[1], "day 1".to_owned())]);
+    let (loaded, _) = SqliteStorage::new(path)
+        .load(Credentials::Password("pw", None))
+        .unwrap();
+    assert_eq!(loaded.entries, diary.entries);
+    assert!(SqliteStorage::is_database(path));
+    // A row moved to another date is refused.
+    let conn = Connection::open(path).unwrap();
+    conn.execute(
+        "UPDATE days SET date = '2024-02-05' WHERE date = '2024-02-01'",
+        [],
+    )
+    .unwrap();
+    assert!(matches!(
+        storage.load(Credentials::Password("pw", None)),
+        Err(DiaryFromFileError::InvalidFormat)
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+#[test]
+fn diary_files_named_like_databases_are_not_databases() {
+    let path = std::env::temp_dir().join(format!("journalr-{}.db", std::process::id()));
+    let path = path.to_str().unwrap();
+    assert!(SqliteStorage::is_database(path));
+    std::fs::write(path, "{\"entries\": {}}").unwrap();
+    assert!(!SqliteStorage::is_database(path));
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,156 @@
+//! Rendering screens and driving the editor without a terminal, for tests.
+//!
+//! Screens are compared as plain text against the snapshots in `src/snapshots`. A missing or
+//! different snapshot fails the test; set `JOURNALR_UPDATE_SNAPSHOTS=1` to record new ones and
+//! re-record the ones that changed on purpose.
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use ratatui::{backend::TestBackend, buffer::Buffer, Terminal};
+use similar::TextDiff;
+
+use crate::{
+    app::{App, AppMode},
+    crypto::DiaryKey,
+    date::Date,
+    diary::Diary,
+    events::ScriptedEvents,
+    secret::PasswordPolicy,
+    storage::{Credentials, MemoryStorage, Storage},
+    ui::{
+        create_file,
+        date_selection::{get_date_ui, DateSelection},
+        delete_ui,
+        editor::{editor_ui, pre_exit_ui},
+        password_form::{password_form_ui, PasswordForm},
+    },
+};
+
+/// Terminal sizes every screen is rendered at: small, common and large.
+pub(crate) const SIZES: [(u16, u16); 3] = [(40, 12), (80, 24), (120, 40)];
+
+/// The text of `buf`, one line per row without trailing spaces. Styles are left out.
+pub(crate) fn text(buf: &Buffer) -> String {
+    let width = buf.area.width as usize;
+    let mut text = String::new();
+    for row in buf.content().chunks(width) {
+        let line: String = row.iter().map(|cell| cell.symbol()).collect();
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+/// Draw with `ui` on a `width` by `height` terminal and return what is on screen.
+pub(crate) fn render(width: u16, height: u16, ui: impl FnOnce(&mut Buffer)) -> String {
+    let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+    terminal.draw(|f| ui(f.buffer_mut())).unwrap();
+    text(terminal.backend().buffer())
+}
+/// Fail with a diff if `actual` is not what the snapshot called `name` holds.
+pub(crate) fn assert_snapshot(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/snapshots")
+        .join(format!("{name}.txt"));
+    let update = std::env::var_os("JOURNALR_UPDATE_SNAPSHOTS").is_some();
+    match fs::read_to_string(&path) {
+        Ok(expected) if expected == actual => (),
+        Ok(expected) if !update => panic!(
+            "{name} differs from its snapshot:\n{}",
+            TextDiff::from_lines(expected.as_str(), actual).unified_diff()
+        ),
+        Err(_) if !update => panic!(
+            "{name} has no snapshot; rerun with JOURNALR_UPDATE_SNAPSHOTS=1 to record it:\n{actual}"
+        ),
+        _ => {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, actual).unwrap();
+        }
+    }
+}
+/// Render `ui` at each of [`SIZES`] and compare against the snapshots `<name>_<w>x<h>`.
+pub(crate) fn assert_screen(name: &str, mut ui: impl FnMut(&mut Buffer)) {
+    for (width, height) in SIZES {
+        let screen = render(width, height, &mut ui);
+        assert_snapshot(&format!("{name}_{width}x{height}"), &screen);
+    }
+}
+/// Open `diary` as a plaintext diary on `date`, press `events`, and return what was saved.
+pub(crate) fn edit(diary: &Diary, date: Date, events: ScriptedEvents) -> Diary {
+    let mut storage = MemoryStorage::new();
+    storage.save(diary, &DiaryKey::Plaintext).unwrap();
+    let mut app = App::with_backend(TestBackend::new(80, 24), events).unwrap();
+    app.storage = Box::new(storage.clone());
+    app.date = date;
+    app.try_load().unwrap();
+    app.mode = AppMode::Edit;
+    app.drive().unwrap();
+    drop(app);
+    storage.load(Credentials::Password("", None)).unwrap().0
+}
+
+fn date(s: &str) -> Date {
+    s.parse().unwrap()
+}
+
+#[test]
+fn screens_match_snapshots() {
+    let today = date("03-02-2024");
+    let entries = HashMap::from([
+        (
+            date("01-02-2024"),
+            <App>::input_area(date("01-02-2024"), None),
+        ),
+        (
+            today,
+            <App>::input_area(today, Some(&"Went for a walk.\nIt rained.".to_owned())),
+        ),
+    ]);
+    assert_screen("editor", |buf| {
+        editor_ui(buf, &entries[&today], &entries, &today, false, false, None)
+    });
+    assert_screen("editor_read_only", |buf| {
+        editor_ui(buf, &entries[&today], &entries, &today, true, true, None)
+    });
+    assert_screen("date_picker", |buf| {
+        get_date_ui(buf, &mut DateSelection::new(today))
+    });
+    let mut form = PasswordForm::new(PasswordPolicy::default());
+    for c in "hunter2".chars() {
+        form.input(crossterm::event::KeyEvent::from(
+            crossterm::event::KeyCode::Char(c),
+        ));
+    }
+    assert_screen("password_form", |buf| password_form_ui(&form, buf));
+    assert_screen("delete", |buf| delete_ui(today, buf));
+    assert_screen("create_file", |buf| {
+        create_file(buf.area, buf, "diary.jrnl")
+    });
+    assert_screen("pre_exit", pre_exit_ui);
+}
+
+#[test]
+fn keys_change_the_saved_diary() {
+    use crossterm::event::{KeyCode, KeyModifiers};
+    let mut diary = Diary::new();
+    diary.entries.insert(date("03-02-2024"), "first".to_owned());
+    // Move to the next day, write there, delete the first entry and save.
+    let events = ScriptedEvents::default()
+        .key(KeyCode::Char('d'), KeyModifiers::ALT)
+        .key(KeyCode::Char('+'), KeyModifiers::NONE)
+        .key(KeyCode::Enter, KeyModifiers::NONE)
+        .type_text("second")
+        .key(KeyCode::Char('d'), KeyModifiers::ALT)
+        .key(KeyCode::Char('-'), KeyModifiers::NONE)
+        .key(KeyCode::Enter, KeyModifiers::NONE)
+        .key(KeyCode::Char('r'), KeyModifiers::CONTROL)
+        .key(KeyCode::Char('y'), KeyModifiers::NONE)
+        .key(KeyCode::Char('+'), KeyModifiers::NONE)
+        .key(KeyCode::Enter, KeyModifiers::NONE)
+        .key(KeyCode::Char('s'), KeyModifiers::CONTROL);
+    let saved = edit(&diary, date("03-02-2024"), events);
+    assert_eq!(
+        saved.entries,
+        HashMap::from([(date("04-02-2024"), "second".to_owned())])
+    );
+    assert_eq!(saved.trash.len(), 1);
+    assert_eq!(saved.trash[0].text, "first");
+}
@@ -3,12 +3,16 @@ pub use std::collections::HashMap;
 pub use chrono::{Days, Months};
 pub use ratatui::{
     prelude::*,
-    widgets::{Block, BorderType, Borders, List, ListState},
+    widgets::{Block, BorderType, Borders, Clear, List, ListState, Paragraph, Wrap},
 };
 use text_box::TextBox;
 pub use tui_textarea::{Input, Key, TextArea};
 
-pub use crate::{clear, date::Date};
+pub use crate::{
+    clear,
+    date::Date,
+    secret::{wipe, Zeroizing},
+};
 pub mod text_box {
     use ratatui::{
         buffer::Buffer,
@@ -59,28 +63,80 @@ pub mod text_box {
 }
 pub(crate) mod editor {
     use super::*;
-    pub fn editor_ui<T>(buf: &mut Buffer, ta: &TextArea, entries: &HashMap<Date, T>, date: &Date) {
+    pub fn editor_ui<T>(
+        buf: &mut Buffer,
+        ta: &TextArea,
+        entries: &HashMap<Date, T>,
+        date: &Date,
+        read_only: bool,
+        plaintext: bool,
+        notice: Option<&str>,
+    ) {
         let areas = Layout::new(
             Direction::Horizontal,
             [Constraint::Percentage(65), Constraint::Min(20)],
         )
         .split(buf.area);
         ta.widget().render(areas[0], buf);
-        sidebar(areas[1], buf, entries, date);
+        if read_only {
+            Block::new()
+                .title_bottom(Line::from(" READ-ONLY ").reversed().bold())
+                .title_alignment(Alignment::Center)
+                .render(areas[0], buf);
+        }
+        if plaintext {
+            Block::new()
+                .title(Line::from(" NOT ENCRYPTED ").reversed().bold())
+                .title_alignment(Alignment::Right)
+                .render(areas[0], buf);
+        }
+        if let Some(notice) = notice {
+            // Only the first line, as git errors can go on for several.
+            let notice = notice.lines().next().unwrap_or_default();
+            Block::new()
+                .title_bottom(Line::from(format!(" {notice} ")).red().reversed())
+                .title_alignment(Alignment::Left)
+                .render(areas[0], buf);
+        }
+        sidebar(areas[1], buf, entries, date, read_only);
     }
-    fn sidebar<T>(area: Rect, buf: &mut Buffer, entries: &HashMap<Date, T>, date: &Date) {
+    fn sidebar<T>(
+        area: Rect,
+        buf: &mut Buffer,
+        entries: &HashMap<Date, T>,
+        date: &Date,
+        read_only: bool,
+    ) {
         let areas = Layout::new(
             Direction::Vertical,
             [Constraint::Min(20), Constraint::Percentage(70)],
         )
         .split(area);
         let tb = Block::default().borders(Borders::all());
-        let shortcuts = List::new([
-            "Quit  :  <Esc>",
-            "Save  :  <Ctrl+S>",
-            "Date  :  <Alt+D>",
-            "Delete:  <Ctrl+Delete>",
-        ])
+        let shortcuts = if read_only {
+            List::new([
+                "Quit  :  <Esc>",
+                "Date  :  <Alt+D>",
+                "Trash :  <Alt+T>",
+                "History: <Alt+H>",
+                "Lock  :  <Ctrl+L>",
+                "Suspend: <Ctrl+Z>",
+                "Edit  :  <Alt+R>",
+            ])
+        } else {
+            List::new([
+                "Quit  :  <Esc>",
+                "Save  :  <Ctrl+S>",
+                "Date  :  <Alt+D>",
+                "Delete:  <Ctrl+Delete>",
+                "Undo  :  <Alt+U>",
+                "Trash :  <Alt+T>",
+                "History: <Alt+H>",
+                "Lock  :  <Ctrl+L>",
+                "Suspend: <Ctrl+Z>",
+                "View only: <Alt+R>",
+            ])
+        }
         .block(tb);
         <List as Widget>::render(shortcuts, areas[1], buf);
         let mut entries = entries
@@ -110,7 +166,7 @@ pub(crate) mod editor {
     }
 }
 
-pub(crate) mod date_selection {
+pub mod date_selection {
     use text_box::TextBox;
 
     use super::*;
@@ -135,7 +191,7 @@ pub(crate) mod date_selection {
             }
         }
     }
-    pub(crate) struct DateSelection {
+    pub struct DateSelection {
         date: Date,
         selection: CurrentlySelected,
     }
@@ -272,21 +328,30 @@ pub(crate) mod date_selection {
 }
 
 pub(crate) mod password_form {
+    use ratatui::widgets::LineGauge;
+
     use super::*;
+    use crate::secret::{entropy_bits, PasswordPolicy, Strength};
     enum PasswordFormMode {
         Typing,
         Retyping,
-        Submitted,
+        /// Both fields were left empty; waiting for the user to confirm they want no password.
+        ConfirmEmpty,
     }
     pub struct PasswordForm<'a> {
         og: TextArea<'a>,
         retype: TextArea<'a>,
         mode: PasswordFormMode,
-        matching: bool,
+        policy: PasswordPolicy,
+        /// Why the last submitted password was rejected.
+        error: Option<String>,
+        shown: bool,
+        /// What unlocks the diary without a password, if anything.
+        unlocked_by: Option<&'static str>,
     }
     impl<'a> Default for PasswordForm<'a> {
         fn default() -> Self {
-            Self::new()
+            Self::new(PasswordPolicy::default())
         }
     }
 
@@ -297,70 +362,123 @@ pub(crate) mod password_form {
         fn active_block(block: Block<'a>) -> Block<'a> {
             block.style(Style::new().fg(Color::Gray).bg(Color::DarkGray))
         }
-        pub fn new() -> Self {
+        fn og_ta() -> TextArea<'a> {
+            let mut ta = password_ta(" Enter Password: ");
+            let block = ta.block().unwrap().clone();
+            ta.set_block(block.title_bottom(" <Ctrl+T>: Show/Hide "));
+            ta
+        }
+        pub fn new(policy: PasswordPolicy) -> Self {
             let mut i = Self {
-                og: password_ta(" Enter Password: "),
+                og: Self::og_ta(),
                 retype: password_ta(" Retype password: "),
                 mode: PasswordFormMode::Typing,
-                matching: true,
+                policy,
+                error: None,
+                shown: false,
+                unlocked_by: None,
             };
             i.typing();
             i
         }
-        pub fn input(&mut self, input: impl Into<Input>) -> Option<String> {
-            let enter_key = Input {
-                key: Key::Enter,
+        /// Say that `unlocked_by` still unlocks the diary if no password is chosen.
+        pub fn unlocked_by(mut self, unlocked_by: Option<&'static str>) -> Self {
+            self.unlocked_by = unlocked_by;
+            self
+        }
+        pub fn input(&mut self, input: impl Into<Input>) -> Option<Zeroizing<String>> {
+            let input = input.into();
+            let key = |key| Input {
+                key,
                 ctrl: false,
                 alt: false,
                 shift: false,
             };
-            let input = input.into();
+            if input
+                == (Input {
+                    ctrl: true,
+                    ..key(Key::Char('t'))
+                })
+            {
+                self.toggle_shown();
+                return None;
+            }
             match self.mode {
                 PasswordFormMode::Typing => {
-                    if enter_key == input {
-                        self.og
-                            .set_block(Self::inactive_block(self.og.block().unwrap().clone()));
-                        self.retype
-                            .set_block(Self::active_block(self.retype.block().unwrap().clone()));
-                        self.mode = PasswordFormMode::Retyping;
+                    if key(Key::Enter) == input {
+                        self.retyping();
                     } else {
                         self.og.input(input);
                     }
                     None
                 }
                 PasswordFormMode::Retyping => {
-                    if enter_key == input {
-                        self.mode = PasswordFormMode::Submitted;
+                    if key(Key::Enter) == input {
+                        self.submit()
                     } else {
                         self.retype.input(input);
+                        None
                     }
-                    None
                 }
-                PasswordFormMode::Submitted => {
-                    if self
-                        .og
-                        .lines()
-                        .first()
-                        .map(|x| x.as_str())
-                        .unwrap_or_default()
-                        != self
-                            .retype
-                            .lines()
-                            .first()
-                            .map(|f| f.as_str())
-                            .unwrap_or_default()
-                    {
-                        self.matching = false;
-                        clear(&mut self.og);
-                        clear(&mut self.retype);
+                PasswordFormMode::ConfirmEmpty => {
+                    if key(Key::Char('y')) == input {
+                        Some(Zeroizing::new(String::new()))
+                    } else if [key(Key::Char('n')), key(Key::Esc)].contains(&input) {
                         self.typing();
                         None
                     } else {
-                        Some(self.og.lines()[0].clone())
+                        None
                     }
                 }
             }
         }
+        fn password(&self) -> &str {
+            self.og
+                .lines()
+                .first()
+                .map(|x| x.as_str())
+                .unwrap_or_default()
+        }
+        fn submit(&mut self) -> Option<Zeroizing<String>> {
+            let retyped = self.retype.lines().first().map(|x| x.as_str());
+            let error = if self.password() != retyped.unwrap_or_default() {
+                "Passwords don't match".to_owned()
+            } else if self.password().is_empty() {
+                self.mode = PasswordFormMode::ConfirmEmpty;
+                return None;
+            } else {
+                match self.policy.check(self.password()) {
+                    Ok(()) => return Some(Zeroizing::new(self.password().to_owned())),
+                    Err(e) => e,
+                }
+            };
+            self.error = Some(error);
+            self.reset();
+            None
+        }
+        /// Clear both fields and start over.
+        fn reset(&mut self) {
+            wipe(std::mem::replace(&mut self.og, Self::og_ta()));
+            wipe(std::mem::replace(
+                &mut self.retype,
+                password_ta(" Retype password: "),
+            ));
+            self.set_mask();
+            self.typing();
+        }
+        fn toggle_shown(&mut self) {
+            self.shown = !self.shown;
+            self.set_mask();
+        }
+        fn set_mask(&mut self) {
+            for ta in [&mut self.og, &mut self.retype] {
+                if self.shown {
+                    ta.clear_mask_char();
+                } else {
+                    ta.set_mask_char('*');
+                }
+            }
+        }
         pub fn retyping(&mut self) {
             self.og
                 .set_block(Self::inactive_block(self.og.block().unwrap().clone()));
@@ -377,11 +495,40 @@ pub(crate) mod password_form {
         }
     }
 
+    impl Drop for PasswordForm<'_> {
+        fn drop(&mut self) {
+            wipe(std::mem::take(&mut self.og));
+            wipe(std::mem::take(&mut self.retype));
+        }
+    }
+
     impl Widget for &PasswordForm<'_> {
         fn render(self, area: Rect, buf: &mut Buffer)
         where
             Self: Sized,
         {
+            if let PasswordFormMode::ConfirmEmpty = self.mode {
+                let lines = match self.unlocked_by {
+                    Some(unlocked_by) => vec![
+                        Line::from("Create the diary without a password? (y/n)").bold(),
+                        Line::from(""),
+                        Line::from(format!(
+                            "It will still be encrypted; {unlocked_by} unlocks it."
+                        )),
+                        Line::from("A password can be added later with `journalr rekey`."),
+                    ],
+                    None => vec![
+                        Line::from("Store the diary unencrypted? (y/n)").bold(),
+                        Line::from(""),
+                        Line::from(
+                            "It will be saved as plain JSON that anyone with the file can read.",
+                        ),
+                        Line::from("It can be encrypted later with `journalr convert`."),
+                    ],
+                };
+                TextBox::from(lines).render(area, buf);
+                return;
+            }
             let areas = Layout::new(
                 Direction::Vertical,
                 [
@@ -393,28 +540,200 @@ pub(crate) mod password_form {
             )
             .split(area);
             self.og.widget().render(areas[0], buf);
+            let bits = entropy_bits(self.password());
+            let strength = Strength::of(bits);
+            let color = match strength {
+                Strength::Empty | Strength::VeryWeak => Color::Red,
+                Strength::Weak => Color::LightRed,
+                Strength::Fair => Color::Yellow,
+                Strength::Strong | Strength::VeryStrong => Color::Green,
+            };
+            LineGauge::default()
+                .ratio((bits / 128.0).clamp(0.0, 1.0))
+                .label(format!("{strength} ({bits:.0} bits) "))
+                .gauge_style(Style::new().fg(color))
+                .render(areas[1], buf);
             self.retype.widget().render(areas[2], buf);
-            if !self.matching {
-                Line::raw("Passwords don't match")
-                    .centered()
-                    .render(areas[3], buf);
+            if let Some(error) = &self.error {
+                Line::raw(error.as_str()).centered().render(areas[3], buf);
             }
         }
     }
     pub fn password_form_ui(pf: &PasswordForm, buf: &mut Buffer) {
+        // The middle 40%, but at least enough for both fields, the strength and the error.
+        let margin = |total: u16, least: u16| {
+            let size = (total * 2 / 5).max(least).min(total);
+            Constraint::Length((total - size) / 2)
+        };
         let area = centered(
             buf.area,
-            Constraint::Percentage(30),
-            Constraint::Percentage(30),
+            margin(buf.area.width, 32),
+            margin(buf.area.height, 8),
         );
         pf.render(area, buf);
     }
-    fn password_ta<'a>(title: impl Into<Line<'a>>) -> TextArea<'a> {
-        let mut ta = TextArea::default();
-        ta.set_block(Block::bordered().title_top(title));
-        ta.set_mask_char('*');
-        ta.set_cursor_line_style(Style::default().fg(ratatui::style::Color::Gray));
-        ta
+}
+
+/// A masked single line input that keeps no undo history of what is typed.
+pub(crate) fn password_ta<'a>(title: impl Into<Line<'a>>) -> TextArea<'a> {
+    let mut ta = TextArea::default();
+    ta.set_block(Block::bordered().title_top(title));
+    ta.set_mask_char('*');
+    ta.set_cursor_line_style(Style::default().fg(ratatui::style::Color::Gray));
+    ta.set_max_histories(0);
+    ta
+}
+
+pub(crate) mod history {
+    use super::*;
+    use crate::diary::Revision;
+    use chrono::Local;
+    use similar::{ChangeTag, TextDiff};
+
+    /// Earlier versions of the entry for `date`, newest first, next to a line diff of the
+    /// selected version against `present`.
+    pub(crate) fn history_ui(
+        buf: &mut Buffer,
+        date: Date,
+        revisions: &[Revision],
+        present: &str,
+        selected: usize,
+    ) {
+        let areas = Layout::new(
+            Direction::Horizontal,
+            [Constraint::Percentage(35), Constraint::Min(20)],
+        )
+        .split(buf.area);
+        let items = revisions
+            .iter()
+            .rev()
+            .map(|r| {
+                format!(
+                    "Until {}",
+                    r.replaced.with_timezone(&Local).format("%d-%m-%Y %H:%M")
+                )
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items)
+            .block(
+                Block::bordered()
+                    .title_top(format!(" History of {} ", date.friendly_format()))
+                    .title_bottom(" <Enter>: Restore | <Esc>: Back ")
+                    .bold(),
+            )
+            .highlight_style(Style::new().bg(Color::Gray).fg(Color::White));
+        let mut state =
+            ListState::default().with_selected((!revisions.is_empty()).then_some(selected));
+        <List as StatefulWidget>::render(list, areas[0], buf, &mut state);
+        let block = Block::bordered().title_top(" Changes since this version ");
+        let Some(revision) = revisions.iter().rev().nth(selected) else {
+            Paragraph::new("No earlier versions of this entry")
+                .block(block)
+                .render(areas[1], buf);
+            return;
+        };
+        let lines = TextDiff::from_lines(revision.text.as_str(), present)
+            .iter_all_changes()
+            .map(|change| {
+                let text = change.value().trim_end_matches('\n').to_owned();
+                match change.tag() {
+                    ChangeTag::Delete => Line::from(format!("- {text}")).red(),
+                    ChangeTag::Insert => Line::from(format!("+ {text}")).green(),
+                    ChangeTag::Equal => Line::from(format!("  {text}")),
+                }
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(block)
+            .render(areas[1], buf);
+    }
+}
+
+pub(crate) mod merge {
+    use super::*;
+    use crate::diary::Conflict;
+
+    /// Both sides of a merge conflict next to each other.
+    pub(crate) fn conflict_ui(
+        buf: &mut Buffer,
+        conflict: &Conflict,
+        (index, total): (usize, usize),
+        (ours_name, theirs_name): (&str, &str),
+    ) {
+        let border = Block::bordered()
+            .title_top(format!(
+                " Conflict {}/{total}: {} ",
+                index + 1,
+                conflict.date.friendly_format()
+            ))
+            .title_bottom(
+                " <Left>: Keep left | <Right>: Keep right | b: Keep both | <Esc>: Keep both for the rest ",
+            )
+            .title_alignment(Alignment::Center)
+            .bold();
+        let area = border.inner(buf.area);
+        border.render(buf.area, buf);
+        let areas = Layout::new(
+            Direction::Horizontal,
+            [Constraint::Percentage(50), Constraint::Percentage(50)],
+        )
+        .split(area);
+        for (side, name, area) in [
+            (&conflict.ours, ours_name, areas[0]),
+            (&conflict.theirs, theirs_name, areas[1]),
+        ] {
+            Paragraph::new(side.as_deref().unwrap_or("(deleted)"))
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title_top(format!(" {name} ")))
+                .render(area, buf);
+        }
+    }
+}
+
+pub(crate) mod trash {
+    use super::*;
+    use crate::diary::TrashedEntry;
+    use chrono::Local;
+
+    /// Trashed entries, newest first, with a preview of the selected one.
+    pub(crate) fn trash_ui(buf: &mut Buffer, trash: &[TrashedEntry], selected: usize) {
+        let areas = Layout::new(
+            Direction::Horizontal,
+            [Constraint::Percentage(35), Constraint::Min(20)],
+        )
+        .split(buf.area);
+        let items = trash
+            .iter()
+            .rev()
+            .map(|t| {
+                format!(
+                    "{}  (deleted {})",
+                    t.date,
+                    t.deleted.with_timezone(&Local).format("%d-%m-%Y %H:%M")
+                )
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items)
+            .block(
+                Block::bordered()
+                    .title_top(" Trash ")
+                    .title_bottom(" <Enter>: Restore | <Esc>: Back ")
+                    .bold(),
+            )
+            .highlight_style(Style::new().bg(Color::Gray).fg(Color::White));
+        let mut state = ListState::default().with_selected((!trash.is_empty()).then_some(selected));
+        <List as StatefulWidget>::render(list, areas[0], buf, &mut state);
+        let preview = trash
+            .iter()
+            .rev()
+            .nth(selected)
+            .map(|t| t.text.as_str())
+            .unwrap_or("The trash is empty");
+        Paragraph::new(preview)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered())
+            .render(areas[1], buf);
     }
 }
 
@@ -425,6 +744,76 @@ pub fn delete_ui(date: Date, buf: &mut Buffer) {
     ))
     .render(buf.area, buf);
 }
+pub fn external_change_ui(path: &str, refused: bool, buf: &mut Buffer) {
+    let reload = match refused {
+        true => "r: Not reloaded; it is no longer encrypted (use `journalr convert`)",
+        false => "r: Reload it, discarding unsaved changes",
+    };
+    TextBox::from(vec![
+        Line::from(format!("\"{path}\" was changed outside journalr.")).bold(),
+        Line::from(""),
+        Line::from(reload),
+        Line::from("m: Merge it with your changes"),
+        Line::from("o: Overwrite it with your version"),
+        Line::from("<Esc>: Keep editing"),
+    ])
+    .render(buf.area, buf);
+}
+pub fn lock_held_ui(path: &str, holder: Option<&crate::lock::LockHolder>, buf: &mut Buffer) {
+    let by = match holder {
+        Some(holder) => format!("by {holder}"),
+        None => format!("by someone; \"{path}.lock\" cannot be read"),
+    };
+    TextBox::from(vec![
+        Line::from(format!("\"{path}\" is already open for editing")).bold(),
+        Line::from(by),
+        Line::from(""),
+        Line::from("r: Open it read-only"),
+        Line::from("b: Break the lock and edit anyway"),
+        Line::from("<Esc>: Quit"),
+    ])
+    .render(buf.area, buf);
+}
+pub fn offer_recovery_key_ui(buf: &mut Buffer) {
+    TextBox::from(vec![
+        Line::from("Generate a recovery key? (y/n)").bold(),
+        Line::from(""),
+        Line::from("It unlocks the diary if you forget the password."),
+    ])
+    .render(buf.area, buf);
+}
+/// The recovery key of a new diary, shown only this once.
+pub fn recovery_key_ui(buf: &mut Buffer, key: &str) {
+    TextBox::from(vec![
+        Line::from("Recovery key").bold(),
+        Line::from(""),
+        Line::from(key).bold(),
+        Line::from(""),
+        Line::from("Write it down and keep it somewhere safe; it will not be shown again."),
+        Line::from("Use it with `journalr recover` to set a new password."),
+        Line::from(""),
+        Line::from("<Enter>: Continue"),
+    ])
+    .render(buf.area, buf);
+}
+/// Unlock history shown after the password is entered, so guessing does not go unnoticed.
+pub fn unlock_notice_ui(buf: &mut Buffer, summary: &str) {
+    let mut lines = summary.lines().map(Line::from).collect::<Vec<_>>();
+    lines.push(Line::from(""));
+    lines.push(Line::from("Press any key to continue"));
+    TextBox::from(lines).render(buf.area, buf);
+}
+/// Offer to restore the changes a session that ended without saving left in the journal.
+pub fn recover_journal_ui(buf: &mut Buffer, taken: &str, entries: usize) {
+    TextBox::from(vec![
+        Line::from("Recover unsaved changes? (y/n)").bold(),
+        Line::from(""),
+        Line::from(format!(
+            "Changes to {entries} entries, last recorded {taken}, were never saved."
+        )),
+    ])
+    .render(buf.area, buf);
+}
 pub fn create_file(area: Rect, buf: &mut Buffer, path: &str) {
     TextBox::from(format!("Do you want to create \"{path}\" ? (y/n)")).render(area, buf)
 }
@@ -438,6 +827,17 @@ pub fn centered_input_box(ta: &TextArea<'_>, buf: &mut Buffer) {
     ta.widget().render(area, buf);
 }
 
+/// Lock screen: nothing but the password prompt, so no diary text stays visible.
+pub fn lock_ui(ta: &TextArea<'_>, buf: &mut Buffer) {
+    let area = buf.area;
+    Clear.render(area, buf);
+    Block::new()
+        .title_bottom(" journalr is locked ")
+        .title_alignment(Alignment::Center)
+        .render(area, buf);
+    centered_input_box(ta, buf);
+}
+
 pub fn centered(area: Rect, horizontal_margin: Constraint, vertical_margin: Constraint) -> Rect {
     Layout::new(
         Direction::Vertical,
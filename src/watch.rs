@@ -0,0 +1,75 @@
+//! Noticing when the diary file is changed by someone else while it is open.
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    time::SystemTime,
+};
+
+/// What the diary file looked like when it was last loaded or saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+impl FileStamp {
+    pub fn of(path: &str) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Self {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            hash: hash(&fs::read(path)?),
+        })
+    }
+    /// The stamp of a diary that is not kept in a file.
+    pub fn of_contents(bytes: &[u8]) -> Self {
+        Self {
+            modified: None,
+            len: bytes.len() as u64,
+            hash: hash(bytes),
+        }
+    }
+    /// The current stamp of `path` if its contents differ from when `self` was taken.
+    ///
+    /// Only the metadata is checked unless it changed, so this is cheap to call often. When
+    /// only the metadata changed, as after a `touch`, `self` takes it over so the contents are
+    /// not read again next time.
+    pub fn changed(&mut self, path: &str) -> io::Result<Option<Self>> {
+        let meta = fs::metadata(path)?;
+        if meta.modified().ok() == self.modified && meta.len() == self.len {
+            return Ok(None);
+        }
+        let now = Self::of(path)?;
+        if now.hash != self.hash {
+            return Ok(Some(now));
+        }
+        *self = now;
+        Ok(None)
+    }
+}
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn touching_refreshes_the_stamp() {
+    let path = std::env::temp_dir().join(format!("journalr-watch-{}.jrnl", std::process::id()));
+    fs::write(&path, "diary").unwrap();
+    let path_str = path.to_str().unwrap();
+    let mut stamp = FileStamp::of(path_str).unwrap();
+    let later = SystemTime::now() + std::time::Duration::from_secs(60);
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+    assert_eq!(stamp.changed(path_str).unwrap(), None);
+    assert_eq!(stamp.modified, Some(later));
+    fs::write(&path, "changed").unwrap();
+    assert!(stamp.changed(path_str).unwrap().is_some());
+    fs::remove_file(path).unwrap();
+}